@@ -1,62 +1,253 @@
-use anyhow::Result;
-use std::str::FromStr;
+use anyhow::{anyhow, Result};
+use aoc::Interval;
+use itertools::Itertools;
+use std::{cmp::Reverse, collections::BTreeSet, str::FromStr};
 
 struct Zone {
-    start: u32,
-    end: u32,
+    sections: Interval,
 }
 
-struct Pair {
-    left: Zone,
-    right: Zone,
+/// The zones of the elves listed on one line.
+struct Group {
+    zones: Vec<Zone>,
 }
 
 impl FromStr for Zone {
     type Err = anyhow::Error;
 
+    /// Either `start-end`, in any order, or a single section.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        return match s.split_once("-") {
-            Some((start, end)) => Ok(Self {
-                start: start.parse()?,
-                end: end.parse()?,
-            }),
-            _ => panic!("Error deserializing Zone: {s}"),
+        let section = |section: &str| {
+            section
+                .trim()
+                .parse::<u32>()
+                .map_err(|err| anyhow!("Invalid section {section:?} in zone {s:?}: {err}"))
         };
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (section(start)?, section(end)?),
+            None => (section(s)?, section(s)?),
+        };
+        Ok(Self {
+            sections: Interval::new(start.min(end), start.max(end)).unwrap(),
+        })
     }
 }
 
 impl Zone {
     fn is_contained(&self, other: &Self) -> bool {
-        return other.start <= self.start && other.end >= self.end;
+        return other.sections.contains(&self.sections);
     }
 }
 
-impl FromStr for Pair {
+impl FromStr for Group {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        return match s.split_once(",") {
-            Some((left, right)) => Ok(Self {
-                left: left.parse()?,
-                right: right.parse()?,
-            }),
-            _ => panic!("Error parsing lines"),
-        };
+        let zones = s
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Zone>>>()?;
+        if zones.len() < 2 {
+            return Err(anyhow!(
+                "Expected at least two zones separated by ',': {s:?}"
+            ));
+        }
+        Ok(Self { zones })
     }
 }
 
-impl Pair {
+impl Group {
+    /// Whether the zone of any elf in the group contains the zone of another.
     fn contained(&self) -> bool {
-        return self.left.is_contained(&self.right) || self.right.is_contained(&self.left);
+        self.zones
+            .iter()
+            .tuple_combinations()
+            .any(|(a, b)| a.is_contained(b) || b.is_contained(a))
     }
 
     fn have_overlap(&self) -> bool {
-        return self.left.start <= self.right.start && self.left.end >= self.right.start
-            || (self.right.start <= self.left.start && self.right.end >= self.left.start);
+        self.zones
+            .iter()
+            .tuple_combinations()
+            .any(|(a, b)| a.sections.overlaps(&b.sections))
+    }
+}
+
+struct Elf {
+    line: usize,
+    zone: Interval,
+}
+
+/// Every elf's zone in input order, with the line it was read from.
+fn elves(input: &str) -> Result<Vec<Elf>> {
+    let mut elves = vec![];
+    for (i, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let group = line
+            .parse::<Group>()
+            .map_err(|err| anyhow!("Line {}: {err}", i + 1))?;
+        elves.extend(group.zones.into_iter().map(|zone| Elf {
+            line: i + 1,
+            zone: zone.sections,
+        }));
+    }
+    Ok(elves)
+}
+
+fn zones_of(elves: &[Elf]) -> Vec<Interval> {
+    elves.iter().map(|elf| elf.zone).collect()
+}
+
+/// The sections covered by at least one zone, as disjoint intervals in
+/// order with a gap between each of them.
+fn merge(zones: &[Interval]) -> Vec<Interval> {
+    let mut sorted = zones.to_vec();
+    sorted.sort();
+    let mut merged: Vec<Interval> = vec![];
+    for zone in sorted {
+        match merged.last_mut() {
+            Some(last) if last.touches(&zone) => *last = last.union(&zone).unwrap(),
+            _ => merged.push(zone),
+        }
+    }
+    merged
+}
+
+fn covered_sections(merged: &[Interval]) -> u64 {
+    merged.iter().map(Interval::length).sum()
+}
+
+/// The gaps of `merged` within `universe`.
+fn uncovered_sections(merged: &[Interval], universe: Interval) -> Vec<Interval> {
+    let mut uncovered = vec![universe];
+    for zone in merged {
+        let Some(rest) = uncovered.pop() else {
+            break;
+        };
+        uncovered.extend(rest.difference(zone));
     }
+    uncovered
+}
+
+/// The sections nobody covers, counting from section 1 up to the last one
+/// covered.
+fn unassigned_sections(merged: &[Interval]) -> Vec<Interval> {
+    match merged.last().and_then(|last| Interval::new(1, last.end())) {
+        Some(universe) => uncovered_sections(merged, universe),
+        None => vec![],
+    }
+}
+
+/// The section covered by the most zones with how many cover it, the first
+/// one on ties. Sweeps over the zone ends instead of walking every section.
+fn most_covered(zones: &[Interval]) -> Option<(u32, usize)> {
+    let mut events = zones
+        .iter()
+        .flat_map(|zone| {
+            [
+                (u64::from(zone.start()), 1),
+                (u64::from(zone.end()) + 1, -1),
+            ]
+        })
+        .collect::<Vec<(u64, isize)>>();
+    events.sort();
+
+    let mut best: Option<(u32, usize)> = None;
+    let mut depth = 0;
+    for (i, (section, delta)) in events.iter().enumerate() {
+        depth += delta;
+        let last_at_section = events.get(i + 1).is_none_or(|next| next.0 != *section);
+        if last_at_section && depth > 0 && best.is_none_or(|(_, count)| depth as usize > count) {
+            best = Some((*section as u32, depth as usize));
+        }
+    }
+    best
+}
+
+fn elves_covering(zones: &[Interval], section: u32) -> Vec<usize> {
+    zones
+        .iter()
+        .enumerate()
+        .filter(|(_, zone)| zone.contains_point(section))
+        .map(|(elf, _)| elf)
+        .collect()
+}
+
+/// Sweeps the zones by start, longest first on ties, keeping the zones already
+/// seen ordered by end. Every zone seen before `zone` starts no later, so the
+/// ones ending at or after `from` are found with a single range query, which
+/// lists every pair in O(n log n + k) for k pairs, smaller index first.
+fn sweep_pairs(zones: &[Interval], from: impl Fn(&Interval) -> u32) -> Vec<(usize, usize)> {
+    let mut order = (0..zones.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| (zones[i].start(), Reverse(zones[i].end()), i));
+
+    let mut pairs = vec![];
+    let mut seen = BTreeSet::new();
+    for i in order {
+        for &(_, j) in seen.range((from(&zones[i]), 0)..) {
+            pairs.push((i.min(j), i.max(j)));
+        }
+        seen.insert((zones[i].end(), i));
+    }
+    pairs
+}
+
+/// Every pair of elves, across the whole input, whose zones overlap.
+fn overlapping_pairs(zones: &[Interval]) -> Vec<(usize, usize)> {
+    sweep_pairs(zones, |zone| zone.start())
+}
+
+/// Every pair of elves, across the whole input, where one zone contains the
+/// other.
+fn containing_pairs(zones: &[Interval]) -> Vec<(usize, usize)> {
+    sweep_pairs(zones, |zone| zone.end())
 }
 
 fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    if let [_, command, path] = &args[..] {
+        if command == "pairs" {
+            let zones = zones_of(&elves(&std::fs::read_to_string(path)?)?);
+            println!("{} overlapping pairs", overlapping_pairs(&zones).len());
+            println!("{} containing pairs", containing_pairs(&zones).len());
+            return Ok(());
+        }
+    }
+
+    if let [_, command, path, section @ ..] = &args[..] {
+        if command == "coverage" {
+            let elves = elves(&std::fs::read_to_string(path)?)?;
+            let zones = zones_of(&elves);
+            match section {
+                [] => {
+                    let merged = merge(&zones);
+                    println!("{} sections covered", covered_sections(&merged));
+                    for gap in unassigned_sections(&merged) {
+                        println!("Nobody covers sections {}-{}", gap.start(), gap.end());
+                    }
+                    if let Some((section, count)) = most_covered(&zones) {
+                        println!("Section {section} is covered by {count} elves");
+                    }
+                }
+                [section] => {
+                    for elf in elves_covering(&zones, section.parse()?) {
+                        let Elf { line, zone } = elves[elf];
+                        println!(
+                            "Elf {} on line {line}: {}-{}",
+                            elf + 1,
+                            zone.start(),
+                            zone.end()
+                        );
+                    }
+                }
+                _ => return Err(anyhow!("Usage: coverage FILE [SECTION]")),
+            }
+            return Ok(());
+        }
+    }
+
     let solution_1 = solve_part_one();
 
     println!("The solution of part one is {:?}", solution_1);
@@ -71,8 +262,8 @@ fn main() -> Result<()> {
 fn solve_part_one() -> Result<usize> {
     let included_zones = include_str!("./day4.input")
         .lines()
-        .map(|line| return line.parse::<Pair>().expect("Error parsing zones pairs"))
-        .filter(Pair::contained)
+        .map(|line| return line.parse::<Group>().expect("Error parsing zones pairs"))
+        .filter(Group::contained)
         .count();
 
     return Ok(included_zones);
@@ -81,10 +272,214 @@ fn solve_part_one() -> Result<usize> {
 fn solve_part_two() -> Result<usize> {
     let included_zones = include_str!("./day4.input")
         .lines()
-        .map(|line| return line.parse::<Pair>().expect("Error parsing zones pairs"))
-        .filter(Pair::have_overlap)
+        .map(|line| return line.parse::<Group>().expect("Error parsing zones pairs"))
+        .filter(Group::have_overlap)
         .count();
 
     return Ok(included_zones);
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::{
+        containing_pairs, covered_sections, elves, elves_covering, merge, most_covered,
+        overlapping_pairs, solve_part_one, solve_part_two, unassigned_sections, uncovered_sections,
+        zones_of, Group,
+    };
+    use aoc::Interval;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn pair(s: &str) -> Group {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_example() {
+        let example = [
+            "2-4,6-8", "2-3,4-5", "5-7,7-9", "2-8,3-7", "6-6,4-6", "2-6,4-8",
+        ];
+        let pairs = example.map(pair);
+        assert_eq!(pairs.iter().filter(|p| p.contained()).count(), 2);
+        assert_eq!(pairs.iter().filter(|p| p.have_overlap()).count(), 4);
+    }
+
+    #[test]
+    fn test_touching_zones() {
+        assert!(!pair("2-4,5-6").have_overlap());
+        assert!(!pair("5-6,2-4").have_overlap());
+        assert!(pair("2-4,4-6").have_overlap());
+        assert!(pair("4-6,2-4").have_overlap());
+        assert!(!pair("2-4,4-6").contained());
+    }
+
+    #[test]
+    fn test_single_section_zones() {
+        assert!(pair("5-5,5-5").contained());
+        assert!(pair("3-3,1-9").contained());
+        assert!(pair("1-9,9-9").contained());
+        assert!(pair("1-9,9-9").have_overlap());
+        assert!(!pair("4-4,5-5").have_overlap());
+        assert!(pair("8-3,4-4").contained());
+    }
+
+    #[test]
+    fn test_solutions() {
+        assert_eq!(solve_part_one().unwrap(), 528);
+        assert_eq!(solve_part_two().unwrap(), 881);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let zones = zones_of(&elves("2-4,6-8\n2-3,4-5\n12-12,7-9\n").unwrap());
+        let interval = |start, end| Interval::new(start, end).unwrap();
+        let merged = merge(&zones);
+        assert_eq!(merged, vec![interval(2, 9), interval(12, 12)]);
+        assert_eq!(covered_sections(&merged), 9);
+        assert_eq!(
+            uncovered_sections(&merged, interval(1, 12)),
+            vec![interval(1, 1), interval(10, 11)]
+        );
+        assert_eq!(uncovered_sections(&merged, interval(2, 9)), vec![]);
+        assert_eq!(most_covered(&zones), Some((2, 2)));
+        assert_eq!(elves_covering(&zones, 4), vec![0, 3]);
+        assert_eq!(elves_covering(&zones, 8), vec![1, 5]);
+        assert_eq!(elves_covering(&zones, 10), vec![]);
+        assert_eq!(most_covered(&[]), None);
+
+        let zones = zones_of(&elves("0-0,0-0\n").unwrap());
+        let merged = merge(&zones);
+        assert_eq!(merged, vec![Interval::point(0)]);
+        assert_eq!(uncovered_sections(&merged, Interval::point(0)), vec![]);
+        assert_eq!(unassigned_sections(&merged), vec![]);
+        assert_eq!(unassigned_sections(&[]), vec![]);
+
+        let merged = merge(&zones_of(&elves("3-4,8-9\n").unwrap()));
+        assert_eq!(
+            unassigned_sections(&merged),
+            vec![Interval::new(1, 2).unwrap(), Interval::new(5, 7).unwrap()]
+        );
+        assert_eq!(most_covered(&zones), Some((0, 2)));
+    }
+
+    #[test]
+    fn test_coverage_matches_counting() {
+        let mut rng = StdRng::seed_from_u64(48);
+        for _ in 0..200 {
+            let zones = (0..rng.gen_range(0..12))
+                .map(|_| {
+                    let start = rng.gen_range(1..40);
+                    Interval::new(start, start + rng.gen_range(0..8)).unwrap()
+                })
+                .collect::<Vec<_>>();
+            let counts = (0..50)
+                .map(|section| elves_covering(&zones, section).len())
+                .collect::<Vec<_>>();
+
+            let merged = merge(&zones);
+            assert!(merged.windows(2).all(|w| !w[0].touches(&w[1])));
+            let covered = counts.iter().filter(|count| **count > 0).count();
+            assert_eq!(covered_sections(&merged), covered as u64);
+            let uncovered = uncovered_sections(&merged, Interval::new(0, 49).unwrap());
+            let gaps = uncovered.iter().map(Interval::length).sum::<u64>();
+            assert_eq!(gaps as usize, 50 - covered);
+
+            let max = counts.iter().max().copied().unwrap();
+            let expected = (max > 0).then(|| {
+                let section = counts.iter().position(|count| *count == max).unwrap();
+                (section as u32, max)
+            });
+            assert_eq!(most_covered(&zones), expected);
+        }
+    }
+
+    fn brute_force_pairs(
+        zones: &[Interval],
+        related: impl Fn(&Interval, &Interval) -> bool,
+    ) -> Vec<(usize, usize)> {
+        let mut pairs = vec![];
+        for i in 0..zones.len() {
+            for j in i + 1..zones.len() {
+                if related(&zones[i], &zones[j]) {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        pairs
+    }
+
+    fn sorted(mut pairs: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn test_pairs_match_brute_force() {
+        let zones = zones_of(&elves("2-4,6-8\n2-3,4-5\n5-7,7-9\n").unwrap());
+        assert_eq!(
+            sorted(overlapping_pairs(&zones)),
+            vec![(0, 2), (0, 3), (1, 4), (1, 5), (3, 4), (4, 5)]
+        );
+        assert_eq!(sorted(containing_pairs(&zones)), vec![(0, 2)]);
+
+        let mut rng = StdRng::seed_from_u64(49);
+        for _ in 0..300 {
+            let zones = (0..rng.gen_range(0..40))
+                .map(|_| {
+                    let start = rng.gen_range(0..30);
+                    Interval::new(start, start + rng.gen_range(0..10)).unwrap()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                sorted(overlapping_pairs(&zones)),
+                brute_force_pairs(&zones, Interval::overlaps)
+            );
+            assert_eq!(
+                sorted(containing_pairs(&zones)),
+                brute_force_pairs(&zones, |a, b| a.contains(b) || b.contains(a))
+            );
+        }
+
+        let elves = elves(include_str!("./day4.input")).unwrap();
+        let zones = zones_of(&elves);
+        let within_lines = |pairs: Vec<(usize, usize)>| {
+            let same_line = |(i, j): &&(usize, usize)| elves[*i].line == elves[*j].line;
+            pairs.iter().filter(same_line).count()
+        };
+        assert_eq!(within_lines(containing_pairs(&zones)), 528);
+        assert_eq!(within_lines(overlapping_pairs(&zones)), 881);
+    }
+
+    #[test]
+    fn test_flexible_lines() {
+        let group = pair(" 2 - 4 ,6-8, 7 ,9-7");
+        let sections = group.zones.iter().map(|zone| zone.sections);
+        let sections = sections.map(|i| (i.start(), i.end())).collect::<Vec<_>>();
+        assert_eq!(sections, vec![(2, 4), (6, 8), (7, 7), (7, 9)]);
+        assert!(group.contained());
+        assert!(group.have_overlap());
+
+        assert!(!pair("1-2,4-5,7-8").have_overlap());
+        assert!(pair("1-2,4-5,8-7,2").have_overlap());
+        assert!(pair("1-2,4-5,8-7,2").contained());
+        assert!(!pair("1-3,4-5,3-4").contained());
+
+        for line in [
+            "",
+            "2-4",
+            "2-4,",
+            "2-4,x-6",
+            "2-4,6-",
+            "2-4,1-2-3",
+            "2-4,-1-3",
+            "2-4;6-8",
+        ] {
+            assert!(line.parse::<Group>().is_err(), "{line:?}");
+        }
+
+        let err = elves("2-4,6-8\n2-4,6-x\n").err().unwrap();
+        assert!(err.to_string().starts_with("Line 2: "), "{err}");
+        let elves = elves("2-4,6-8,7\n\n1-1,3-2\n").unwrap();
+        let lines = elves.iter().map(|elf| elf.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![1, 1, 1, 3, 3]);
+    }
+}
@@ -1,86 +1,459 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fmt,
+    io::{BufRead, Write},
+    ops::Index,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
 use itertools::Itertools;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 //use itertools::Itertools;
 
-#[derive(Debug)]
+/// The stacks are kept in ascending label order, `stacks[i]` being the one
+/// labelled `labels[i]`.
+#[derive(Debug, Clone, PartialEq)]
 struct Crane {
-    stack: HashMap<u8, Vec<char>>,
+    labels: Vec<usize>,
+    stacks: Vec<Vec<String>>,
+}
+
+#[derive(Debug, PartialEq)]
+enum MoveError {
+    MissingStack {
+        line: usize,
+        stack: usize,
+    },
+    NotEnoughCrates {
+        line: usize,
+        stack: usize,
+        height: usize,
+        quantity: usize,
+    },
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoveError::MissingStack { line, stack } => {
+                write!(f, "Line {line}: stack {stack} does not exist")
+            }
+            MoveError::NotEnoughCrates {
+                line,
+                stack,
+                height,
+                quantity,
+            } => write!(
+                f,
+                "Line {line}: cannot take {quantity} crates from stack {stack}, which has {height}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+/// How a crane puts down the crates it takes from the origin stack.
+/// Moving crates onto the stack they came from leaves it as it was, whatever
+/// the model, so `arrange` only sees moves between two different stacks.
+trait CraneModel {
+    fn name(&self) -> String;
+
+    /// Turns the crates taken from the origin, listed bottom to top, into
+    /// the order they end up in on the destiny.
+    fn arrange(&self, taken: Vec<String>) -> Vec<String>;
+
+    /// Inverse of `arrange`, turning the crates put down on the destiny back
+    /// into the order they had on the origin.
+    fn unarrange(&self, placed: Vec<String>) -> Vec<String>;
+}
+
+/// Moves crates one at a time, reversing them.
+struct CrateMover9000;
+
+/// Moves all the crates at once, keeping their order.
+struct CrateMover9001;
+
+/// Moves up to `capacity` crates at once, so larger moves are split into
+/// several lifts, the topmost group going first.
+struct CappedBulkMover {
+    capacity: usize,
+}
+
+/// Moves all the crates at once, but flips every group of `k` crates,
+/// counting from the top.
+struct ReversingMover {
+    k: usize,
+}
+
+impl CraneModel for CrateMover9000 {
+    fn name(&self) -> String {
+        "CrateMover 9000".to_string()
+    }
+
+    fn arrange(&self, mut taken: Vec<String>) -> Vec<String> {
+        taken.reverse();
+        taken
+    }
+
+    fn unarrange(&self, placed: Vec<String>) -> Vec<String> {
+        self.arrange(placed)
+    }
+}
+
+impl CraneModel for CrateMover9001 {
+    fn name(&self) -> String {
+        "CrateMover 9001".to_string()
+    }
+
+    fn arrange(&self, taken: Vec<String>) -> Vec<String> {
+        taken
+    }
+
+    fn unarrange(&self, placed: Vec<String>) -> Vec<String> {
+        placed
+    }
+}
+
+impl CraneModel for CappedBulkMover {
+    fn name(&self) -> String {
+        format!("capped bulk mover ({} crates)", self.capacity)
+    }
+
+    fn arrange(&self, mut taken: Vec<String>) -> Vec<String> {
+        let mut placed = Vec::with_capacity(taken.len());
+        while !taken.is_empty() {
+            let lift = taken.split_off(taken.len().saturating_sub(self.capacity));
+            placed.extend(lift);
+        }
+        placed
+    }
+
+    fn unarrange(&self, mut placed: Vec<String>) -> Vec<String> {
+        let mut taken = Vec::with_capacity(placed.len());
+        while !placed.is_empty() {
+            let lift = placed.split_off((placed.len() - 1) / self.capacity * self.capacity);
+            taken.extend(lift);
+        }
+        taken
+    }
+}
+
+impl CraneModel for ReversingMover {
+    fn name(&self) -> String {
+        format!("reversing mover ({} crates)", self.k)
+    }
+
+    fn arrange(&self, mut taken: Vec<String>) -> Vec<String> {
+        taken.rchunks_mut(self.k).for_each(|group| group.reverse());
+        taken
+    }
+
+    fn unarrange(&self, placed: Vec<String>) -> Vec<String> {
+        self.arrange(placed)
+    }
+}
+
+/// Looks a crane model up by name: `9000`, `9001`, `capped:K` or
+/// `reverse:K`.
+fn crane_model(name: &str) -> Result<Box<dyn CraneModel>> {
+    let parameter = |value: &str| -> Result<usize> {
+        match value.parse()? {
+            0 => Err(anyhow!("Crane model {name} needs a positive size")),
+            value => Ok(value),
+        }
+    };
+
+    match name.split_once(':') {
+        None if name == "9000" => Ok(Box::new(CrateMover9000)),
+        None if name == "9001" => Ok(Box::new(CrateMover9001)),
+        Some(("capped", capacity)) => Ok(Box::new(CappedBulkMover {
+            capacity: parameter(capacity)?,
+        })),
+        Some(("reverse", k)) => Ok(Box::new(ReversingMover { k: parameter(k)? })),
+        _ => Err(anyhow!("Unknown crane model {name}")),
+    }
 }
 
 impl Crane {
-    fn move_one_by_one(&mut self, movement: &Move) -> &Self {
-        let _removed_ements: Vec<_> = (0..movement.quantity)
-            .map(|_| {
-                let item = self
-                    .stack
-                    .get_mut(&movement.origin)
-                    .expect("Origin must exists")
-                    .pop()
-                    .unwrap();
-                self.stack
-                    .get_mut(&movement.destiny)
-                    .expect("Destiny must exists")
-                    .push(item);
-            })
-            .collect();
+    /// Index in `stacks` of the stack with the given label. Labels are usually
+    /// `1..=n`, which is checked first to avoid the search.
+    fn position(&self, label: usize) -> Option<usize> {
+        match self.labels.get(label.wrapping_sub(1)) {
+            Some(found) if *found == label => Some(label - 1),
+            _ => self.labels.binary_search(&label).ok(),
+        }
+    }
 
-        self
+    /// Checks `movement` against the current state without applying it, and
+    /// returns the positions of its origin and destiny stacks.
+    fn check(&self, movement: &Move) -> Result<(usize, usize), MoveError> {
+        let destiny = self
+            .position(movement.destiny)
+            .ok_or(MoveError::MissingStack {
+                line: movement.line,
+                stack: movement.destiny,
+            })?;
+        let origin = self
+            .position(movement.origin)
+            .ok_or(MoveError::MissingStack {
+                line: movement.line,
+                stack: movement.origin,
+            })?;
+
+        let height = self.stacks[origin].len();
+        if height < movement.quantity {
+            return Err(MoveError::NotEnoughCrates {
+                line: movement.line,
+                stack: movement.origin,
+                height,
+                quantity: movement.quantity,
+            });
+        }
+
+        Ok((origin, destiny))
     }
 
-    fn move_in_bulk(&mut self, movement: &Move) -> &Self {
-        let removed_ements = (0..movement.quantity)
-            .map(|_| {
-                self.stack
-                    .get_mut(&movement.origin)
-                    .expect("Origin must exists")
-                    .pop()
-                    .unwrap()
-            })
-            .collect::<Vec<_>>();
+    fn apply(&mut self, model: &dyn CraneModel, movement: &Move) -> Result<(), MoveError> {
+        let (origin, destiny) = self.check(movement)?;
+        if origin == destiny {
+            return Ok(());
+        }
+        let height = self.stacks[origin].len();
+        let taken = self.stacks[origin].split_off(height - movement.quantity);
+        self.stacks[destiny].extend(model.arrange(taken));
 
-        removed_ements
-            .into_iter()
-            .rev()
-            .for_each(|x| self.stack.get_mut(&movement.destiny).unwrap().push(x));
+        Ok(())
+    }
+
+    /// Undoes `movement`, taking its crates back from the destiny to the
+    /// origin. Fails when the destiny does not hold enough crates, which means
+    /// the move cannot have been the last one applied.
+    fn unapply(&mut self, model: &dyn CraneModel, movement: &Move) -> Result<(), MoveError> {
+        let (destiny, origin) = self.check(&Move {
+            origin: movement.destiny,
+            destiny: movement.origin,
+            ..movement.clone()
+        })?;
+        if origin == destiny {
+            return Ok(());
+        }
+        let height = self.stacks[destiny].len();
+        let placed = self.stacks[destiny].split_off(height - movement.quantity);
+        self.stacks[origin].extend(model.unarrange(placed));
+
+        Ok(())
+    }
+
+    /// Applies the moves in order, stopping at the first invalid one.
+    fn run(&mut self, movements: &[Move], model: &dyn CraneModel) -> Result<(), MoveError> {
+        movements
+            .iter()
+            .try_for_each(|movement| self.apply(model, movement))
+    }
+
+    /// Applies every valid move and skips the invalid ones, returning all of
+    /// the errors found along the way.
+    fn run_collecting_errors(
+        &mut self,
+        movements: &[Move],
+        model: &dyn CraneModel,
+    ) -> Vec<MoveError> {
+        movements
+            .iter()
+            .filter_map(|movement| self.apply(model, movement).err())
+            .collect()
+    }
+
+    fn top_of_stacks(&self) -> String {
+        let mut result = String::new();
+        self.stacks.iter().for_each(|v| {
+            if let Some(c) = v.last() {
+                result.push_str(c);
+            }
+        });
+        result
+    }
+}
+
+impl Index<usize> for Crane {
+    type Output = Vec<String>;
+
+    /// The stack with the given label.
+    fn index(&self, label: usize) -> &Self::Output {
+        &self.stacks[self.position(label).expect("Stack must exists")]
+    }
+}
 
-        self
+/// Splits a line into its whitespace separated words, along with the first
+/// and last column (in characters) each of them spans.
+fn words(line: &str) -> Vec<(usize, usize, String)> {
+    let mut result = vec![];
+    let mut current: Option<(usize, String)> = None;
+    for (column, c) in line.chars().enumerate() {
+        match (c.is_whitespace(), current.as_mut()) {
+            (false, Some((_, word))) => word.push(c),
+            (false, None) => current = Some((column, c.to_string())),
+            (true, Some(_)) => {
+                let (start, word) = current.take().unwrap();
+                result.push((start, column - 1, word));
+            }
+            (true, None) => {}
+        }
+    }
+    if let Some((start, word)) = current {
+        result.push((start, start + word.chars().count() - 1, word));
     }
+    result
 }
 
 impl FromStr for Crane {
     type Err = anyhow::Error;
 
+    /// Reads the numbered footer first to learn where each stack's column
+    /// is, then assigns every `[X]` crate above it to the stack label its
+    /// brackets overlap. Lines may be ragged or have their trailing spaces
+    /// stripped, and crate labels may be longer than one character.
     fn from_str(s: &str) -> Result<Self> {
-        let mut stack = HashMap::<u8, Vec<char>>::new();
-
-        for line in s.lines().rev().skip(1) {
-            let mut idx: u8 = 1;
-            let mut i = 0;
-            while i < line.len() {
-                let token = &line[i..(i + 3)];
-                if !token.trim().is_empty() {
-                    let value = token[1..].chars().next().expect("Empty token!");
-                    stack
-                        .entry(idx)
-                        .and_modify(|elements| elements.push(value))
-                        .or_insert(vec![value]);
+        let lines = s.lines().collect::<Vec<_>>();
+        let footer = lines
+            .iter()
+            .rposition(|line| !line.trim().is_empty())
+            .ok_or_else(|| anyhow!("Empty crate drawing"))?;
+
+        let mut columns = vec![];
+        for (start, end, label) in words(lines[footer]) {
+            let id = label
+                .parse::<usize>()
+                .map_err(|_| anyhow!("Line {}: invalid stack label {label}", footer + 1))?;
+            columns.push((start, end, id));
+        }
+        let mut stacks = vec![vec![]; columns.len()];
+
+        for (row, line_idx) in (0..footer).rev().enumerate() {
+            for (start, end, token) in words(lines[line_idx]) {
+                let line_number = line_idx + 1;
+                let value = token
+                    .strip_prefix('[')
+                    .and_then(|token| token.strip_suffix(']'))
+                    .filter(|value| !value.is_empty() && !value.contains(['[', ']']))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Line {line_number}: invalid crate {token} at column {}",
+                            start + 1
+                        )
+                    })?;
+
+                let (position, id) = match columns
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (label_start, label_end, _))| {
+                        start <= *label_end && *label_start <= end
+                    })
+                    .collect::<Vec<_>>()[..]
+                {
+                    [(position, (_, _, id))] => (position, *id),
+                    [] => {
+                        return Err(anyhow!(
+                            "Line {line_number}: crate {token} is not above any stack label"
+                        ))
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "Line {line_number}: crate {token} spans several stack labels"
+                        ))
+                    }
+                };
+
+                let elements = &mut stacks[position];
+                if elements.len() != row {
+                    return Err(anyhow!(
+                        "Line {line_number}: crate {token} floats above an empty slot in stack {id}"
+                    ));
                 }
-                i += 4;
-                idx += 1;
+                elements.push(value.to_string());
             }
         }
 
-        return Ok(Crane { stack });
+        let (labels, stacks): (Vec<_>, Vec<_>) = columns
+            .into_iter()
+            .map(|(_, _, id)| id)
+            .zip(stacks)
+            .sorted_by_key(|(id, _)| *id)
+            .unzip();
+        if let Some(id) = labels.windows(2).find(|ids| ids[0] == ids[1]) {
+            return Err(anyhow!(
+                "Line {}: duplicated stack label {}",
+                footer + 1,
+                id[0]
+            ));
+        }
+
+        Ok(Crane { labels, stacks })
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for Crane {
+    /// Draws the stacks in the puzzle's `[X]` column format, footer included,
+    /// so that parsing the output gives back the same crane. Columns widen to
+    /// fit crate or stack labels longer than the usual single character.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn centered(text: &str, width: usize) -> String {
+            let padding = width - text.chars().count();
+            let left = padding.div_ceil(2);
+            format!("{}{text}{}", " ".repeat(left), " ".repeat(padding - left))
+        }
+
+        let columns = self
+            .labels
+            .iter()
+            .zip(&self.stacks)
+            .map(|(id, crates)| {
+                let width = crates
+                    .iter()
+                    .map(|c| c.chars().count() + 2)
+                    .chain([3, id.to_string().len()])
+                    .max()
+                    .unwrap();
+                (id, crates, width)
+            })
+            .collect::<Vec<_>>();
+        let height = columns
+            .iter()
+            .map(|(_, crates, _)| crates.len())
+            .max()
+            .unwrap_or(0);
+
+        for row in (0..height).rev() {
+            let line = columns
+                .iter()
+                .map(|(_, crates, width)| match crates.get(row) {
+                    Some(c) => centered(&format!("[{c}]"), *width),
+                    None => " ".repeat(*width),
+                })
+                .join(" ");
+            writeln!(f, "{line}")?;
+        }
+
+        let footer = columns
+            .iter()
+            .map(|(id, _, width)| centered(&id.to_string(), *width))
+            .join(" ");
+        write!(f, "{footer}")
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Move {
     quantity: usize,
-    origin: u8,
-    destiny: u8,
+    origin: usize,
+    destiny: usize,
+    /// Line of the input the move was read from, for error reporting.
+    line: usize,
 }
 
 impl FromStr for Move {
@@ -92,13 +465,723 @@ impl FromStr for Move {
                 quantity: q.parse()?,
                 origin: o.parse()?,
                 destiny: d.parse()?,
+                line: 0,
             }),
             _ => Err(anyhow!("Imposible parse {s}")),
         }
     }
 }
 
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "move {} from {} to {}",
+            self.quantity, self.origin, self.destiny
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    caption: String,
+    drawing: String,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n{}", self.caption, self.drawing)
+    }
+}
+
+/// Every intermediate state of a procedure, rendered up front so the replay
+/// can be stepped in both directions.
+struct Replay {
+    frames: Vec<Frame>,
+    position: usize,
+}
+
+impl Replay {
+    /// Runs the procedure on a copy of `crane`, keeping a frame after each
+    /// move. With `per_crate` each move is split into single crate moves, which
+    /// only matches the real procedure for the CrateMover 9000.
+    fn record(
+        crane: &Crane,
+        movements: &[Move],
+        model: &dyn CraneModel,
+        per_crate: bool,
+    ) -> Result<Self, MoveError> {
+        let mut crane = crane.clone();
+        let mut frames = vec![Frame {
+            caption: "Initial state".to_string(),
+            drawing: crane.to_string(),
+        }];
+
+        for (i, movement) in movements.iter().enumerate() {
+            crane.check(movement)?;
+            let steps = if per_crate {
+                vec![
+                    Move {
+                        quantity: 1,
+                        ..movement.clone()
+                    };
+                    movement.quantity
+                ]
+            } else {
+                vec![movement.clone()]
+            };
+
+            for (j, step) in steps.iter().enumerate() {
+                crane.apply(model, step)?;
+                let mut caption = format!(
+                    "Move {}/{}: {movement} (line {})",
+                    i + 1,
+                    movements.len(),
+                    movement.line
+                );
+                if per_crate {
+                    caption.push_str(&format!(", crate {}/{}", j + 1, steps.len()));
+                }
+                frames.push(Frame {
+                    caption,
+                    drawing: crane.to_string(),
+                });
+            }
+        }
+
+        Ok(Self {
+            frames,
+            position: 0,
+        })
+    }
+
+    fn current(&self) -> &Frame {
+        &self.frames[self.position]
+    }
+
+    fn forward(&mut self) -> &Frame {
+        self.position = (self.position + 1).min(self.frames.len() - 1);
+        self.current()
+    }
+
+    fn backward(&mut self) -> &Frame {
+        self.position = self.position.saturating_sub(1);
+        self.current()
+    }
+
+    fn jump(&mut self, position: usize) -> &Frame {
+        self.position = position.min(self.frames.len() - 1);
+        self.current()
+    }
+
+    /// Plays every frame in the terminal, clearing the screen between them.
+    fn animate(&self, out: &mut impl Write, delay: Duration) -> Result<()> {
+        for frame in &self.frames {
+            writeln!(out, "\x1b[2J\x1b[H{frame}")?;
+            out.flush()?;
+            thread::sleep(delay);
+        }
+        Ok(())
+    }
+
+    fn export(&self, out: &mut impl Write) -> Result<()> {
+        for frame in &self.frames {
+            writeln!(out, "{frame}\n")?;
+        }
+        Ok(())
+    }
+
+    /// Reads one command per line: `n` (or nothing) to step forward, `p` to
+    /// step back, a number to jump to that frame and `q` to quit.
+    fn step(&mut self, input: impl BufRead, out: &mut impl Write) -> Result<()> {
+        writeln!(out, "{}", self.current())?;
+        for line in input.lines() {
+            let frame = match line?.trim() {
+                "" | "n" => self.forward(),
+                "p" => self.backward(),
+                "q" => break,
+                other => match other.parse() {
+                    Ok(position) => self.jump(position),
+                    Err(_) => {
+                        writeln!(out, "Unknown command {other}")?;
+                        continue;
+                    }
+                },
+            };
+            writeln!(out, "{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Picks the crane model and frame granularity for a replay: any model name
+/// accepted by `crane_model`, or `9000-crates` for one frame per crate.
+fn replay_mode(mode: &str) -> Result<(Box<dyn CraneModel>, bool)> {
+    match mode {
+        "9000-crates" => Ok((Box::new(CrateMover9000), true)),
+        _ => Ok((crane_model(mode)?, false)),
+    }
+}
+
+/// Applies moves one at a time while keeping every applied move, so they can
+/// be undone and redone. A copy of the crane is kept every `interval` moves so
+/// that any step of a long procedure can be rebuilt from the closest
+/// checkpoint instead of from the start.
+struct History {
+    model: Box<dyn CraneModel>,
+    crane: Crane,
+    movements: Vec<Move>,
+    position: usize,
+    checkpoints: Vec<Crane>,
+    interval: usize,
+}
+
+impl History {
+    fn new(crane: Crane, model: Box<dyn CraneModel>, interval: usize) -> Self {
+        Self {
+            model,
+            checkpoints: vec![crane.clone()],
+            crane,
+            movements: vec![],
+            position: 0,
+            interval: interval.max(1),
+        }
+    }
+
+    /// Applies a new move at the current position, dropping any moves that
+    /// were undone and not redone yet.
+    fn apply(&mut self, movement: Move) -> Result<(), MoveError> {
+        self.crane.apply(self.model.as_ref(), &movement)?;
+        self.movements.truncate(self.position);
+        self.checkpoints.truncate(self.position / self.interval + 1);
+        self.movements.push(movement);
+        self.position += 1;
+        if self.position.is_multiple_of(self.interval) {
+            self.checkpoints.push(self.crane.clone());
+        }
+        Ok(())
+    }
+
+    fn undo(&mut self) -> Option<&Move> {
+        let movement = self.movements.get(self.position.checked_sub(1)?)?;
+        self.crane
+            .unapply(self.model.as_ref(), movement)
+            .expect("Applied moves can always be undone");
+        self.position -= 1;
+        Some(movement)
+    }
+
+    fn redo(&mut self) -> Option<&Move> {
+        let movement = self.movements.get(self.position)?;
+        self.crane
+            .apply(self.model.as_ref(), movement)
+            .expect("Undone moves can always be redone");
+        self.position += 1;
+        Some(movement)
+    }
+
+    /// The state after the first `step` moves, rebuilt from the closest
+    /// checkpoint before it.
+    fn state_at(&self, step: usize) -> Crane {
+        let step = step.min(self.movements.len());
+        let checkpoint = (step / self.interval).min(self.checkpoints.len() - 1);
+        let mut crane = self.checkpoints[checkpoint].clone();
+        for movement in &self.movements[checkpoint * self.interval..step] {
+            crane
+                .apply(self.model.as_ref(), movement)
+                .expect("Recorded moves are valid");
+        }
+        crane
+    }
+
+    /// Moves to the state after the first `step` moves, walking from the
+    /// current state when that is closer than the nearest checkpoint.
+    fn jump(&mut self, step: usize) {
+        let step = step.min(self.movements.len());
+        let from_checkpoint = step % self.interval;
+        if step.abs_diff(self.position) <= from_checkpoint {
+            while self.position < step {
+                self.redo();
+            }
+            while self.position > step {
+                self.undo();
+            }
+        } else {
+            self.crane = self.state_at(step);
+            self.position = step;
+        }
+    }
+
+    fn top_of_stacks_at(&self, step: usize) -> String {
+        self.state_at(step).top_of_stacks()
+    }
+
+    /// Reads one command per line: `u` to undo, `r` to redo, `j N` to jump to
+    /// step N, `t N` to print the top of the stacks after step N, a `move ...`
+    /// line to apply a new move and `q` to quit.
+    fn interact(&mut self, input: impl BufRead, out: &mut impl Write) -> Result<()> {
+        for (i, line) in input.lines().enumerate() {
+            let line = line?;
+            let message = match line.trim().split_once(' ').unwrap_or((line.trim(), "")) {
+                ("u", _) => match self.undo() {
+                    Some(movement) => format!("Undid {movement}"),
+                    None => "Nothing to undo".to_string(),
+                },
+                ("r", _) => match self.redo() {
+                    Some(movement) => format!("Redid {movement}"),
+                    None => "Nothing to redo".to_string(),
+                },
+                ("j", step) => match step.parse() {
+                    Ok(step) => {
+                        self.jump(step);
+                        format!("At step {}", self.position)
+                    }
+                    Err(_) => format!("Unknown command {line}"),
+                },
+                ("t", step) => match step.parse() {
+                    Ok(step) => self.top_of_stacks_at(step),
+                    Err(_) => format!("Unknown command {line}"),
+                },
+                ("move", _) => match line.parse::<Move>() {
+                    Ok(movement) => match self.apply(Move {
+                        line: i + 1,
+                        ..movement
+                    }) {
+                        Ok(()) => format!("Applied {line}"),
+                        Err(err) => err.to_string(),
+                    },
+                    Err(err) => format!("Invalid command {line}: {err}"),
+                },
+                ("q", _) => break,
+                _ => format!("Unknown command {line}"),
+            };
+            writeln!(
+                out,
+                "{message}\nStep {}/{}\n{}",
+                self.position,
+                self.movements.len(),
+                self.crane
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the procedure backwards from its final state to recover the crane it
+/// started from. Fails on the first move that cannot be undone, which means
+/// the final state and the procedure do not match.
+fn reverse_procedure(
+    final_crane: &Crane,
+    movements: &[Move],
+    model: &dyn CraneModel,
+) -> Result<Crane, MoveError> {
+    let mut crane = final_crane.clone();
+    for movement in movements.iter().rev() {
+        crane.unapply(model, movement)?;
+    }
+    Ok(crane)
+}
+
+/// What is known about a crane when only the top of each stack is. Stacks
+/// that were not known to be empty sit on a base of any number of unknown
+/// crates, which are drawn as `?` once a move needs them.
+#[derive(Debug, Clone, PartialEq)]
+struct PartialCrane {
+    crane: Crane,
+    bottomless: Vec<bool>,
+}
+
+impl PartialCrane {
+    const UNKNOWN: &'static str = "?";
+
+    /// Builds the final state from the top crate of each stack in label
+    /// order, `_` marking an empty stack. The tops are separated by commas or
+    /// spaces, which multi-character crates need, or given as one character
+    /// per stack when there is no separator.
+    fn from_tops(labels: &[usize], tops: &str) -> Result<Self> {
+        let is_separator = |c: char| c == ',' || c.is_whitespace();
+        let tops = if tops.trim().contains(is_separator) {
+            tops.split(is_separator)
+                .filter(|top| !top.is_empty())
+                .collect::<Vec<_>>()
+        } else {
+            let tops = tops.trim();
+            tops.char_indices()
+                .map(|(i, c)| &tops[i..i + c.len_utf8()])
+                .collect()
+        };
+        if tops.len() != labels.len() {
+            return Err(anyhow!(
+                "Expected {} stack tops but got {}",
+                labels.len(),
+                tops.len()
+            ));
+        }
+        Ok(Self {
+            crane: Crane {
+                labels: labels.to_vec(),
+                stacks: tops
+                    .iter()
+                    .map(|top| match *top {
+                        "_" => vec![],
+                        top => vec![top.to_string()],
+                    })
+                    .collect(),
+            },
+            bottomless: tops.iter().map(|top| *top != "_").collect(),
+        })
+    }
+
+    /// Undoes `movement`, pulling unknown crates up from the base of the
+    /// destiny when it does not show enough of them.
+    fn unapply(&mut self, model: &dyn CraneModel, movement: &Move) -> Result<(), MoveError> {
+        if let Some(destiny) = self.crane.position(movement.destiny) {
+            let missing = movement
+                .quantity
+                .saturating_sub(self.crane.stacks[destiny].len());
+            if missing > 0 && self.bottomless[destiny] {
+                self.crane.stacks[destiny].splice(
+                    0..0,
+                    std::iter::repeat_n(Self::UNKNOWN.to_string(), missing),
+                );
+            }
+        }
+        self.crane.unapply(model, movement)
+    }
+}
+
+fn reverse_procedure_from_tops(
+    labels: &[usize],
+    tops: &str,
+    movements: &[Move],
+    model: &dyn CraneModel,
+) -> Result<PartialCrane> {
+    let mut crane = PartialCrane::from_tops(labels, tops)?;
+    for movement in movements.iter().rev() {
+        crane.unapply(model, movement)?;
+    }
+    Ok(crane)
+}
+
+/// Searches for a shortest procedure turning `start` into `target` with A*.
+/// Every move touches two stacks, so half the number of stacks that still
+/// differ from the target is a lower bound on the moves left. Gives up once
+/// `max_states` different states have been seen.
+fn shortest_procedure(
+    start: &Crane,
+    target: &Crane,
+    model: &dyn CraneModel,
+    max_states: usize,
+) -> Result<Vec<Move>> {
+    if start.labels != target.labels {
+        return Err(anyhow!("Start and target have different stacks"));
+    }
+    let crates = |crane: &Crane| {
+        crane
+            .stacks
+            .iter()
+            .flatten()
+            .sorted()
+            .cloned()
+            .collect_vec()
+    };
+    if crates(start) != crates(target) {
+        return Err(anyhow!("Start and target hold different crates"));
+    }
+
+    let estimate = |crane: &Crane| {
+        let differing = crane
+            .stacks
+            .iter()
+            .zip(&target.stacks)
+            .filter(|(stack, target)| stack != target)
+            .count();
+        differing.div_ceil(2)
+    };
+
+    let mut states = vec![start.clone()];
+    let mut parents: Vec<Option<(usize, Move)>> = vec![None];
+    let mut distances = vec![0];
+    let mut seen = HashMap::from([(start.stacks.clone(), 0)]);
+    let mut queue = BinaryHeap::from([Reverse((estimate(start), 0, 0))]);
+
+    while let Some(Reverse((_, distance, state))) = queue.pop() {
+        if distance > distances[state] {
+            continue;
+        }
+        if states[state] == *target {
+            let mut movements = vec![];
+            let mut current = state;
+            while let Some((parent, movement)) = &parents[current] {
+                movements.push(movement.clone());
+                current = *parent;
+            }
+            movements.reverse();
+            movements
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, movement)| movement.line = i + 1);
+            return Ok(movements);
+        }
+
+        let current = states[state].clone();
+        let labels = &current.labels;
+        for (origin, destiny) in (0..labels.len()).cartesian_product(0..labels.len()) {
+            if origin == destiny {
+                continue;
+            }
+            for quantity in 1..=current.stacks[origin].len() {
+                let movement = Move {
+                    quantity,
+                    origin: labels[origin],
+                    destiny: labels[destiny],
+                    line: 0,
+                };
+                let mut next = current.clone();
+                next.apply(model, &movement)?;
+
+                let next_distance = distance + 1;
+                let index = match seen.get(&next.stacks) {
+                    Some(&index) if distances[index] <= next_distance => continue,
+                    Some(&index) => index,
+                    None => {
+                        if states.len() >= max_states {
+                            return Err(anyhow!("Gave up after exploring {max_states} states"));
+                        }
+                        seen.insert(next.stacks.clone(), states.len());
+                        states.push(next);
+                        parents.push(None);
+                        distances.push(usize::MAX);
+                        states.len() - 1
+                    }
+                };
+                distances[index] = next_distance;
+                parents[index] = Some((state, movement));
+                queue.push(Reverse((
+                    next_distance + estimate(&states[index]),
+                    next_distance,
+                    index,
+                )));
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "The target cannot be reached with this crane model"
+    ))
+}
+
+/// Spreads `crates` random single letter crates over `stacks` stacks
+/// labelled `1..=stacks`.
+fn generate_crane(rng: &mut StdRng, stacks: usize, crates: usize) -> Crane {
+    let mut crane = Crane {
+        labels: (1..=stacks).collect(),
+        stacks: vec![vec![]; stacks],
+    };
+    for _ in 0..crates {
+        let c = rng.gen_range(b'A'..=b'Z') as char;
+        crane.stacks[rng.gen_range(0..stacks)].push(c.to_string());
+    }
+    crane
+}
+
+/// Random moves that can all be applied to `crane` in order. Validity only
+/// depends on the stack heights, so the procedure works for any crane model.
+fn generate_procedure(
+    rng: &mut StdRng,
+    crane: &Crane,
+    moves: usize,
+    max_quantity: usize,
+) -> Vec<Move> {
+    let mut heights = crane.stacks.iter().map(Vec::len).collect::<Vec<_>>();
+    if heights.iter().all(|height| *height == 0) {
+        return vec![];
+    }
+
+    (0..moves)
+        .map(|i| {
+            let start = rng.gen_range(0..heights.len());
+            let origin = (start..heights.len())
+                .chain(0..start)
+                .find(|origin| heights[*origin] > 0)
+                .expect("Crates are never lost");
+            let destiny = rng.gen_range(0..heights.len());
+            let quantity = rng.gen_range(1..=heights[origin].min(max_quantity.max(1)));
+            heights[origin] -= quantity;
+            heights[destiny] += quantity;
+            Move {
+                quantity,
+                origin: crane.labels[origin],
+                destiny: crane.labels[destiny],
+                line: i + 1,
+            }
+        })
+        .collect()
+}
+
+/// Times generated procedures of growing length on stacks of tens of
+/// thousands of crates. The time per moved crate should stay flat as the
+/// procedure grows if applying moves is linear.
+fn bench(model: &dyn CraneModel, out: &mut impl Write) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(5);
+    let crane = generate_crane(&mut rng, 9, 200_000);
+    writeln!(out, "{}", model.name())?;
+    for moves in [250_000, 500_000, 1_000_000, 2_000_000] {
+        let movements = generate_procedure(&mut rng, &crane, moves, 100);
+        let moved = movements.iter().map(|m| m.quantity).sum::<usize>();
+        let mut crane = crane.clone();
+        let start = Instant::now();
+        crane.run(&movements, model)?;
+        let elapsed = start.elapsed();
+        writeln!(
+            out,
+            "{moves:>9} moves, {moved:>10} crates: {:>8.1?} ({:.2} ns/crate)",
+            elapsed,
+            elapsed.as_nanos() as f64 / moved as f64
+        )?;
+    }
+    Ok(())
+}
+
+/// Splits the puzzle input into the drawing and the procedure, numbering
+/// each move with the line it was read from.
+fn parse_input(input: &str) -> Result<(Crane, Vec<Move>)> {
+    let (crane, movements) = input
+        .split_once("\n\n")
+        .ok_or_else(|| anyhow!("Missing blank line after the crate drawing"))?;
+    let first_line = crane.lines().count() + 2;
+    let movements = movements
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let mut movement = line
+                .parse::<Move>()
+                .map_err(|err| anyhow!("Line {}: {err}", first_line + i))?;
+            movement.line = first_line + i;
+            Ok(movement)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((crane.parse()?, movements))
+}
+
 fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    if let [_, command, path, models @ ..] = &args[..] {
+        if command == "check" {
+            let input = std::fs::read_to_string(path)?;
+            let models = if models.is_empty() {
+                vec!["9000".to_string(), "9001".to_string()]
+            } else {
+                models.to_vec()
+            };
+            for name in models {
+                let model = crane_model(&name)?;
+                let (mut crane, movements) = parse_input(&input)?;
+                let errors = crane.run_collecting_errors(&movements, model.as_ref());
+                println!("{}: {} invalid moves", model.name(), errors.len());
+                errors.iter().for_each(|error| println!("  {error}"));
+            }
+            return Ok(());
+        }
+    }
+
+    if let [_, command, path, name] = &args[..] {
+        if command == "run" {
+            let model = crane_model(name)?;
+            let (mut crane, movements) = parse_input(&std::fs::read_to_string(path)?)?;
+            crane.run(&movements, model.as_ref())?;
+            println!("{}: {}", model.name(), crane.top_of_stacks());
+            return Ok(());
+        }
+    }
+
+    if let [_, command, path, mode, options @ ..] = &args[..] {
+        if ["animate", "export", "step"].contains(&command.as_str()) {
+            let (crane, movements) = parse_input(&std::fs::read_to_string(path)?)?;
+            let (model, per_crate) = replay_mode(mode)?;
+            let mut replay = Replay::record(&crane, &movements, model.as_ref(), per_crate)?;
+            let mut stdout = std::io::stdout();
+            return match (command.as_str(), options) {
+                ("animate", []) => replay.animate(&mut stdout, Duration::from_millis(250)),
+                ("animate", [delay]) => {
+                    replay.animate(&mut stdout, Duration::from_millis(delay.parse()?))
+                }
+                ("export", [out]) => replay.export(&mut std::fs::File::create(out)?),
+                ("step", []) => replay.step(std::io::stdin().lock(), &mut stdout),
+                _ => Err(anyhow!(
+                    "Usage: animate INPUT MODE [DELAY_MS] | export INPUT MODE FILE | step INPUT MODE"
+                )),
+            };
+        }
+    }
+
+    if let [_, command, path, name] = &args[..] {
+        if command == "history" {
+            let (crane, movements) = parse_input(&std::fs::read_to_string(path)?)?;
+            let mut history = History::new(crane, crane_model(name)?, 100);
+            for movement in movements {
+                history.apply(movement)?;
+            }
+            return history.interact(std::io::stdin().lock(), &mut std::io::stdout());
+        }
+    }
+
+    if let [_, command, models @ ..] = &args[..] {
+        if command == "bench" {
+            for name in models {
+                bench(crane_model(name)?.as_ref(), &mut std::io::stdout())?;
+            }
+            return Ok(());
+        }
+    }
+
+    if let [_, command, path, name, tops @ ..] = &args[..] {
+        if command == "reverse" {
+            let model = crane_model(name)?;
+            let (crane, movements) = parse_input(&std::fs::read_to_string(path)?)?;
+            match tops {
+                [] => println!("{}", reverse_procedure(&crane, &movements, model.as_ref())?),
+                [tops] => {
+                    let partial = reverse_procedure_from_tops(
+                        &crane.labels,
+                        tops,
+                        &movements,
+                        model.as_ref(),
+                    )?;
+                    println!("{}", partial.crane);
+                    let bottomless = partial
+                        .crane
+                        .labels
+                        .iter()
+                        .zip(&partial.bottomless)
+                        .filter(|(_, bottomless)| **bottomless)
+                        .map(|(label, _)| label)
+                        .join(", ");
+                    println!("Stacks {bottomless} may hold more unknown crates below");
+                }
+                _ => return Err(anyhow!("Usage: reverse INPUT MODEL [TOPS]")),
+            }
+            return Ok(());
+        }
+    }
+
+    if let [_, command, start, target, name, options @ ..] = &args[..] {
+        if command == "optimize" {
+            let max_states = match options {
+                [] => 1_000_000,
+                [max_states] => max_states.parse()?,
+                _ => return Err(anyhow!("Usage: optimize START TARGET MODEL [MAX_STATES]")),
+            };
+            let start = std::fs::read_to_string(start)?.parse::<Crane>()?;
+            let target = std::fs::read_to_string(target)?.parse::<Crane>()?;
+            shortest_procedure(&start, &target, crane_model(name)?.as_ref(), max_states)?
+                .iter()
+                .for_each(|movement| println!("{movement}"));
+            return Ok(());
+        }
+    }
 
     let solution_1 = solve_part_one();
 
@@ -112,51 +1195,17 @@ fn main() -> Result<()> {
 }
 
 fn solve_part_one() -> Result<String> {
-    let (crane, movements) = include_str!("./day5.input").split_once("\n\n").unwrap();
-    let mut initial_crane: Crane = crane.parse()?;
-    movements
-        .lines()
-        .map(|line| line.parse::<Move>().unwrap())
-        .for_each(|ref m| {
-            initial_crane.move_one_by_one(m);
-        });
+    let (mut initial_crane, movements) = parse_input(include_str!("./day5.input"))?;
+    initial_crane.run(&movements, &CrateMover9000)?;
 
-    let mut result = String::new();
-    initial_crane
-        .stack
-        .into_iter()
-        .sorted_by(|a, b| Ord::cmp(&a.0, &b.0))
-        .for_each(|(_, v)| {
-            if let Some(&c) = v.last() {
-                result.push(c);
-            }
-        });
-
-    return Ok(result);
+    return Ok(initial_crane.top_of_stacks());
 }
 
 fn solve_part_two() -> Result<String> {
-    let (crane, movements) = include_str!("./day5.input").split_once("\n\n").unwrap();
-    let mut initial_crane: Crane = crane.parse()?;
-    movements
-        .lines()
-        .map(|line| line.parse::<Move>().unwrap())
-        .for_each(|ref m| {
-            initial_crane.move_in_bulk(m);
-        });
-
-    let mut result = String::new();
-    initial_crane
-        .stack
-        .into_iter()
-        .sorted_by(|a, b| Ord::cmp(&a.0, &b.0))
-        .for_each(|(_, v)| {
-            if let Some(&c) = v.last() {
-                result.push(c);
-            }
-        });
+    let (mut initial_crane, movements) = parse_input(include_str!("./day5.input"))?;
+    initial_crane.run(&movements, &CrateMover9001)?;
 
-    return Ok(result);
+    return Ok(initial_crane.top_of_stacks());
 }
 
 #[cfg(test)]
@@ -169,5 +1218,619 @@ mod tests {
         let header = include_str!("./day5.test").split_once("\n\n").unwrap().0;
         let crates = header.parse::<Crane>().unwrap();
         println!("This is the result: {:?}", crates);
+        assert_eq!(crates[1], vec!["Z", "N"]);
+        assert_eq!(crates[2], vec!["M", "C", "D"]);
+        assert_eq!(crates[3], vec!["P"]);
+    }
+
+    #[test]
+    fn test_parse_ragged_and_wide_drawings() {
+        let trimmed = include_str!("./day5.test")
+            .split_once("\n\n")
+            .unwrap()
+            .0
+            .lines()
+            .map(str::trim_end)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let crates = trimmed.parse::<Crane>().unwrap();
+        assert_eq!(crates[2], vec!["M", "C", "D"]);
+        assert_eq!(crates[3], vec!["P"]);
+
+        let crates = [
+            "                                        [K]",
+            "[A]                                 [J] [L]",
+            " 1   2   3   4   5   6   7   8   9  10  11",
+        ]
+        .join("\n")
+        .parse::<Crane>()
+        .unwrap();
+        assert_eq!(crates.stacks.len(), 11);
+        assert_eq!(crates[1], vec!["A"]);
+        assert_eq!(crates[10], vec!["J"]);
+        assert_eq!(crates[11], vec!["L", "K"]);
+        assert!(crates[5].is_empty());
+
+        let crates = "[AB] [CD]\n [X]  [Y]\n  1    2".parse::<Crane>().unwrap();
+        assert_eq!(crates[1], vec!["X", "AB"]);
+        assert_eq!(crates[2], vec!["Y", "CD"]);
+    }
+
+    #[test]
+    fn test_parse_malformed_drawings() {
+        assert!("".parse::<Crane>().is_err());
+        assert!("[A]\n 1   x".parse::<Crane>().is_err());
+        assert!("[A] [B]\n 1   1".parse::<Crane>().is_err());
+        assert!("[A] B\n 1   2".parse::<Crane>().is_err());
+        assert!("[A] [B\n 1   2".parse::<Crane>().is_err());
+        assert!("        [A]\n 1   2".parse::<Crane>().is_err());
+        let err = "[A]\n    [B]\n 1   2".parse::<Crane>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Line 1: crate [A] floats above an empty slot in stack 1"
+        );
+    }
+
+    #[test]
+    fn test_procedure_example() {
+        let (mut crane, movements) = parse_input(include_str!("./day5.test")).unwrap();
+        assert_eq!(movements[0].line, 6);
+        crane.run(&movements, &CrateMover9000).unwrap();
+        assert_eq!(crane.top_of_stacks(), "CMZ");
+
+        let (mut crane, movements) = parse_input(include_str!("./day5.test")).unwrap();
+        crane.run(&movements, &CrateMover9001).unwrap();
+        assert_eq!(crane.top_of_stacks(), "MCD");
+    }
+
+    #[test]
+    fn test_invalid_moves() {
+        let input = include_str!("./day5.test")
+            .replace("move 3 from 1 to 3", "move 4 from 1 to 3")
+            .replace("move 1 from 1 to 2", "move 1 from 1 to 4");
+
+        let (mut crane, movements) = parse_input(&input).unwrap();
+        let error = crane.run(&movements, &CrateMover9000).unwrap_err();
+        assert_eq!(
+            error,
+            MoveError::NotEnoughCrates {
+                line: 7,
+                stack: 1,
+                height: 3,
+                quantity: 4
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Line 7: cannot take 4 crates from stack 1, which has 3"
+        );
+        assert_eq!(crane[1], vec!["Z", "N", "D"]);
+
+        let (mut crane, movements) = parse_input(&input).unwrap();
+        let errors = crane.run_collecting_errors(&movements, &CrateMover9001);
+        assert_eq!(
+            errors,
+            vec![
+                MoveError::NotEnoughCrates {
+                    line: 7,
+                    stack: 1,
+                    height: 3,
+                    quantity: 4
+                },
+                MoveError::MissingStack { line: 9, stack: 4 },
+            ]
+        );
+        assert_eq!(crane.top_of_stacks(), "CP");
+    }
+
+    #[test]
+    fn test_render_round_trip() {
+        for input in [include_str!("./day5.test"), include_str!("./day5.input")] {
+            let header = input.split_once("\n\n").unwrap().0;
+            let crane = header.parse::<Crane>().unwrap();
+            assert_eq!(crane.to_string(), header);
+        }
+
+        let (mut crane, movements) = parse_input(include_str!("./day5.test")).unwrap();
+        crane.run(&movements[..2], &CrateMover9000).unwrap();
+        assert_eq!(
+            crane.to_string(),
+            [
+                "        [Z]",
+                "        [N]",
+                "    [C] [D]",
+                "    [M] [P]",
+                " 1   2   3 "
+            ]
+            .join("\n")
+        );
+
+        let wide = [
+            "[AB]                                        ",
+            "[X]                                     [Y] ",
+            " 1   2   3   4   5   6   7   8   9   10  11 ",
+        ]
+        .join("\n");
+        let crane = wide.parse::<Crane>().unwrap();
+        assert_eq!(crane[1], vec!["X", "AB"]);
+        assert_eq!(crane[11], vec!["Y"]);
+        let rendered = crane.to_string();
+        assert_eq!(rendered.parse::<Crane>().unwrap(), crane);
+        assert_eq!(rendered.parse::<Crane>().unwrap().to_string(), rendered);
+    }
+
+    #[test]
+    fn test_replay() {
+        let (crane, movements) = parse_input(include_str!("./day5.test")).unwrap();
+        let mut replay = Replay::record(&crane, &movements, &CrateMover9001, false).unwrap();
+        assert_eq!(replay.frames.len(), movements.len() + 1);
+        assert_eq!(replay.current().drawing, crane.to_string());
+        assert_eq!(
+            replay.forward().caption,
+            "Move 1/4: move 1 from 2 to 1 (line 6)"
+        );
+        replay.backward();
+        assert_eq!(replay.backward().drawing, crane.to_string());
+        let last = replay.jump(usize::MAX).drawing.parse::<Crane>().unwrap();
+        assert_eq!(last.top_of_stacks(), "MCD");
+
+        let mut replay = Replay::record(&crane, &movements, &CrateMover9000, true).unwrap();
+        assert_eq!(replay.frames.len(), 1 + 1 + 3 + 2 + 1);
+        assert_eq!(
+            replay.jump(3).caption,
+            "Move 2/4: move 3 from 1 to 3 (line 7), crate 2/3"
+        );
+
+        let mut out = vec![];
+        replay
+            .step("n\np\n7\nx\nq\nn\n".as_bytes(), &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("Move 2/4: move 3 from 1 to 3 (line 7), crate 2/3\n"));
+        assert!(out.contains("Unknown command x"));
+        assert_eq!(
+            replay.current().caption,
+            "Move 4/4: move 1 from 1 to 2 (line 9), crate 1/1"
+        );
+
+        let mut out = vec![];
+        replay.export(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("Move ").count(), 7);
+        assert!(out.starts_with(&format!("Initial state\n{crane}\n\n")));
+    }
+
+    #[test]
+    fn test_crane_models() {
+        let (crane, movements) = parse_input(include_str!("./day5.test")).unwrap();
+        let tops = |name: &str| {
+            let mut crane = crane.clone();
+            crane
+                .run(&movements, crane_model(name).unwrap().as_ref())
+                .unwrap();
+            crane.top_of_stacks()
+        };
+        assert_eq!(tops("9000"), "CMZ");
+        assert_eq!(tops("9001"), "MCD");
+        assert_eq!(tops("capped:1"), "CMZ");
+        assert_eq!(tops("capped:3"), "MCD");
+        assert_eq!(tops("reverse:1"), "MCD");
+        assert_eq!(tops("reverse:3"), "CMZ");
+        assert!(crane_model("capped:0").is_err());
+        assert!(crane_model("9002").is_err());
+
+        let taken = ["a", "b", "c", "d", "e"].map(String::from).to_vec();
+        assert_eq!(
+            CappedBulkMover { capacity: 2 }.arrange(taken.clone()),
+            ["d", "e", "b", "c", "a"]
+        );
+        assert_eq!(
+            ReversingMover { k: 2 }.arrange(taken.clone()),
+            ["a", "c", "b", "e", "d"]
+        );
+    }
+
+    #[test]
+    fn test_self_moves() {
+        let (crane, _) = parse_input(include_str!("./day5.test")).unwrap();
+        let movement = "move 3 from 2 to 2".parse::<Move>().unwrap();
+        for name in ["9000", "9001", "capped:1", "capped:2", "reverse:2"] {
+            let model = crane_model(name).unwrap();
+            let mut moved = crane.clone();
+            moved.apply(model.as_ref(), &movement).unwrap();
+            assert_eq!(moved, crane, "{name}");
+            moved.unapply(model.as_ref(), &movement).unwrap();
+            assert_eq!(moved, crane, "{name}");
+        }
+
+        let movements = [movement];
+        let bulk = Replay::record(&crane, &movements, &CrateMover9000, false).unwrap();
+        let per_crate = Replay::record(&crane, &movements, &CrateMover9000, true).unwrap();
+        assert_eq!(
+            bulk.frames.last().unwrap().drawing,
+            per_crate.frames.last().unwrap().drawing
+        );
+        assert_eq!(bulk.frames.last().unwrap().drawing, crane.to_string());
+    }
+
+    #[test]
+    fn test_unapply() {
+        let taken = ["a", "b", "c", "d", "e"].map(String::from).to_vec();
+        for name in [
+            "9000",
+            "9001",
+            "capped:2",
+            "capped:3",
+            "reverse:2",
+            "reverse:4",
+        ] {
+            let model = crane_model(name).unwrap();
+            assert_eq!(model.unarrange(model.arrange(taken.clone())), taken);
+        }
+
+        let (crane, movements) = parse_input(include_str!("./day5.test")).unwrap();
+        let mut undone = crane.clone();
+        undone.run(&movements, &CrateMover9000).unwrap();
+        for movement in movements.iter().rev() {
+            undone.unapply(&CrateMover9000, movement).unwrap();
+        }
+        assert_eq!(undone, crane);
+        assert_eq!(
+            crane.clone().unapply(&CrateMover9001, &movements[1]),
+            Err(MoveError::NotEnoughCrates {
+                line: 7,
+                stack: 3,
+                height: 1,
+                quantity: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let (crane, movements) = parse_input(include_str!("./day5.input")).unwrap();
+        let mut history = History::new(crane.clone(), Box::new(CrateMover9001), 64);
+        let mut tops = vec![crane.top_of_stacks()];
+        for movement in movements.iter().cloned() {
+            history.apply(movement).unwrap();
+            tops.push(history.crane.top_of_stacks());
+        }
+        assert_eq!(history.crane.top_of_stacks(), solve_part_two().unwrap());
+        assert_eq!(history.checkpoints.len(), movements.len() / 64 + 1);
+
+        for step in [0, 1, 63, 64, 65, 300, movements.len()] {
+            assert_eq!(history.top_of_stacks_at(step), tops[step]);
+        }
+
+        history.undo();
+        history.undo();
+        assert_eq!(history.crane.top_of_stacks(), tops[movements.len() - 2]);
+        history.redo();
+        assert_eq!(history.crane.top_of_stacks(), tops[movements.len() - 1]);
+
+        for step in [0, 5, 400, 130, 129, movements.len(), 3] {
+            history.jump(step);
+            assert_eq!(history.position, step);
+            assert_eq!(history.crane, history.state_at(step));
+            assert_eq!(history.crane.top_of_stacks(), tops[step]);
+        }
+
+        while history.undo().is_some() {}
+        assert_eq!(history.crane, crane);
+        assert!(history.undo().is_none());
+
+        history.jump(200);
+        let origin = history
+            .crane
+            .labels
+            .iter()
+            .find(|label| !history.crane[**label].is_empty())
+            .unwrap();
+        let movement = format!("move 1 from {origin} to {origin}");
+        history.apply(movement.parse().unwrap()).unwrap();
+        assert_eq!(history.movements.len(), 201);
+        assert_eq!(history.checkpoints.len(), 200 / 64 + 1);
+        assert!(history.redo().is_none());
+
+        let mut out = vec![];
+        history
+            .interact(
+                "u\nr\nr\nj 0\nt 201\nmove 99 from 1 to 2\nj x\nt\nmove 1 from a to 2\nj 1\nq\n"
+                    .as_bytes(),
+                &mut out,
+            )
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(&format!("Undid {movement}\nStep 200/201\n")));
+        assert!(out.contains("Nothing to redo"));
+        assert!(out.contains("Step 0/201"));
+        assert!(out.contains("Line 6: cannot take 99 crates from stack 1"));
+        assert!(out.contains("Unknown command j x\n"));
+        assert!(out.contains("Unknown command t\n"));
+        assert!(out.contains("Invalid command move 1 from a to 2: "));
+        assert!(out.contains("At step 1\nStep 1/201"));
+    }
+
+    #[test]
+    fn test_generated_procedures() {
+        let mut rng = StdRng::seed_from_u64(37);
+        let crane = generate_crane(&mut rng, 12, 20_000);
+        assert_eq!(crane.labels, (1..=12).collect::<Vec<_>>());
+        let movements = generate_procedure(&mut rng, &crane, 20_000, 500);
+
+        assert!(movements
+            .iter()
+            .any(|m| m.origin == m.destiny && m.quantity > 1));
+
+        // Reference implementations, one popping and pushing a crate at a time
+        // and one lifting all of them together.
+        let reference = |bulk: bool| {
+            let mut stacks = crane.stacks.clone();
+            for movement in &movements {
+                if bulk {
+                    let origin = &mut stacks[movement.origin - 1];
+                    let taken = origin.split_off(origin.len() - movement.quantity);
+                    stacks[movement.destiny - 1].extend(taken);
+                } else {
+                    for _ in 0..movement.quantity {
+                        let taken = stacks[movement.origin - 1].pop().unwrap();
+                        stacks[movement.destiny - 1].push(taken);
+                    }
+                }
+            }
+            stacks
+        };
+
+        let mut one_by_one = crane.clone();
+        one_by_one.run(&movements, &CrateMover9000).unwrap();
+        assert_eq!(one_by_one.stacks, reference(false));
+
+        let mut bulk = crane.clone();
+        bulk.run(&movements, &CrateMover9001).unwrap();
+        assert_eq!(bulk.stacks, reference(true));
+
+        let sparse = "[A]     [B]\n 2   5   9".parse::<Crane>().unwrap();
+        assert_eq!(sparse.labels, vec![2, 5, 9]);
+        assert_eq!(sparse.position(9), Some(2));
+        assert_eq!(sparse.position(1), None);
+        assert_eq!(sparse[9], vec!["B"]);
+        assert_eq!(sparse.top_of_stacks(), "AB");
+    }
+
+    #[test]
+    fn test_reverse_procedure() {
+        let (crane, movements) = parse_input(include_str!("./day5.input")).unwrap();
+        for name in ["9000", "9001", "capped:4", "reverse:3"] {
+            let model = crane_model(name).unwrap();
+            let mut final_crane = crane.clone();
+            final_crane.run(&movements, model.as_ref()).unwrap();
+            assert_eq!(
+                reverse_procedure(&final_crane, &movements, model.as_ref()).unwrap(),
+                crane
+            );
+
+            let partial = reverse_procedure_from_tops(
+                &crane.labels,
+                &final_crane.top_of_stacks(),
+                &movements,
+                model.as_ref(),
+            )
+            .unwrap();
+            let mut replayed = partial.crane.clone();
+            replayed.run(&movements, model.as_ref()).unwrap();
+            assert_eq!(replayed.top_of_stacks(), final_crane.top_of_stacks());
+            for (known, actual) in partial.crane.stacks.iter().zip(&crane.stacks) {
+                assert!(known.len() <= actual.len());
+                for (known, actual) in known.iter().rev().zip(actual.iter().rev()) {
+                    assert!(known == PartialCrane::UNKNOWN || known == actual);
+                }
+            }
+        }
+
+        let (crane, movements) = parse_input("[A]\n 1   2\n\nmove 2 from 2 to 1").unwrap();
+        assert_eq!(
+            reverse_procedure(&crane, &movements, &CrateMover9001),
+            Err(MoveError::NotEnoughCrates {
+                line: 4,
+                stack: 1,
+                height: 1,
+                quantity: 2
+            })
+        );
+
+        let (_, movements) = parse_input(include_str!("./day5.test")).unwrap();
+        let partial =
+            reverse_procedure_from_tops(&[1, 2, 3], "CMZ", &movements, &CrateMover9000).unwrap();
+        assert_eq!(
+            partial.crane.to_string(),
+            ["    [?]    ", "[?] [C]    ", "[Z] [M]    ", " 1   2   3 "].join("\n")
+        );
+        assert_eq!(partial.bottomless, vec![true, true, true]);
+        assert!(
+            reverse_procedure_from_tops(&[1, 2, 3], "C_Z", &movements, &CrateMover9000).is_err()
+        );
+        assert!(PartialCrane::from_tops(&[1, 2, 3], "CM").is_err());
+        assert!(PartialCrane::from_tops(&[1, 2, 3], "C, M").is_err());
+
+        let crane = "[A]    \n[B] [C]\n 1   2 ".parse::<Crane>().unwrap();
+        let movements = ["move 1 from 2 to 1".parse::<Move>().unwrap()];
+        let mut final_crane = crane.clone();
+        final_crane.run(&movements, &CrateMover9000).unwrap();
+        assert!(final_crane[2].is_empty());
+        for tops in ["C_", "C _", "C,_", " C , _ "] {
+            let partial =
+                reverse_procedure_from_tops(&[1, 2], tops, &movements, &CrateMover9000).unwrap();
+            assert_eq!(partial.crane.stacks, vec![vec![], vec!["C"]]);
+            assert_eq!(partial.bottomless, vec![true, false]);
+        }
+
+        let partial = PartialCrane::from_tops(&[1, 2, 3], "AB,_,Q").unwrap();
+        assert_eq!(partial.crane.stacks, vec![vec!["AB"], vec![], vec!["Q"]]);
+        assert_eq!(partial.bottomless, vec![true, false, true]);
+    }
+
+    #[test]
+    fn test_shortest_procedure() {
+        let (start, movements) = parse_input(include_str!("./day5.test")).unwrap();
+        for name in ["9000", "9001", "capped:2"] {
+            let model = crane_model(name).unwrap();
+            let mut target = start.clone();
+            target.run(&movements, model.as_ref()).unwrap();
+
+            let shortest = shortest_procedure(&start, &target, model.as_ref(), 100_000).unwrap();
+            assert!(shortest.len() <= movements.len());
+            let mut reached = start.clone();
+            reached.run(&shortest, model.as_ref()).unwrap();
+            assert_eq!(reached, target);
+
+            let printed = shortest.iter().join("\n");
+            let parsed = printed
+                .lines()
+                .map(|line| line.parse::<Move>().unwrap())
+                .collect::<Vec<_>>();
+            let mut reached = start.clone();
+            reached.run(&parsed, model.as_ref()).unwrap();
+            assert_eq!(reached, target);
+        }
+
+        let swapped = "[A] [B]\n 1   2   3".parse::<Crane>().unwrap();
+        let target = "[B] [A]\n 1   2   3".parse::<Crane>().unwrap();
+        let shortest = shortest_procedure(&swapped, &target, &CrateMover9000, 1_000).unwrap();
+        assert_eq!(shortest.len(), 3);
+        let mut reached = swapped.clone();
+        reached.run(&shortest, &CrateMover9000).unwrap();
+        assert_eq!(reached, target);
+        assert!(shortest_procedure(&swapped, &swapped, &CrateMover9001, 1)
+            .unwrap()
+            .is_empty());
+
+        let two_stacks = "[A] [B]\n 1   2".parse::<Crane>().unwrap();
+        let unreachable = "[B] [A]\n 1   2".parse::<Crane>().unwrap();
+        assert!(shortest_procedure(&two_stacks, &unreachable, &CrateMover9000, 1_000).is_err());
+        let other = "[A] [C]\n 1   2".parse::<Crane>().unwrap();
+        assert!(shortest_procedure(&two_stacks, &other, &CrateMover9000, 1_000).is_err());
+        let (target, _) = parse_input(include_str!("./day5.input")).unwrap();
+        let mut start = target.clone();
+        start.stacks.rotate_left(1);
+        assert!(shortest_procedure(&start, &target, &CrateMover9001, 100).is_err());
+    }
+
+    /// A crane with sparse, possibly multi-digit stack labels and crate labels
+    /// of up to three characters.
+    fn random_crane(rng: &mut StdRng) -> Crane {
+        let mut labels = (0..rng.gen_range(1..=12))
+            .map(|_| rng.gen_range(1..=150))
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels.dedup();
+        let stacks = labels
+            .iter()
+            .map(|_| {
+                (0..rng.gen_range(0..=8))
+                    .map(|_| {
+                        (0..rng.gen_range(1..=3))
+                            .map(|_| rng.gen_range(b'A'..=b'Z') as char)
+                            .collect::<String>()
+                    })
+                    .collect()
+            })
+            .collect();
+        Crane { labels, stacks }
+    }
+
+    fn crate_count(crane: &Crane) -> usize {
+        crane.stacks.iter().map(Vec::len).sum()
+    }
+
+    const MODELS: [&str; 6] = [
+        "9000",
+        "9001",
+        "capped:2",
+        "capped:3",
+        "reverse:2",
+        "reverse:3",
+    ];
+
+    #[test]
+    fn prop_render_round_trip() {
+        for seed in 0..300 {
+            let crane = random_crane(&mut StdRng::seed_from_u64(seed));
+            let rendered = crane.to_string();
+            let parsed = rendered.parse::<Crane>().unwrap();
+            assert_eq!(parsed, crane, "seed {seed}:\n{rendered}");
+            assert_eq!(parsed.to_string(), rendered);
+
+            let trimmed = rendered.lines().map(str::trim_end).join("\n");
+            assert_eq!(trimmed.parse::<Crane>().unwrap(), crane, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn prop_models_conserve_crates() {
+        for seed in 0..100 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let crane = random_crane(&mut rng);
+            let movements = generate_procedure(&mut rng, &crane, 50, 10);
+            for name in MODELS {
+                let mut moved = crane.clone();
+                moved
+                    .run(&movements, crane_model(name).unwrap().as_ref())
+                    .unwrap();
+                assert_eq!(crate_count(&moved), crate_count(&crane), "seed {seed}");
+                assert_eq!(
+                    moved.stacks.iter().flatten().sorted().collect_vec(),
+                    crane.stacks.iter().flatten().sorted().collect_vec()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn prop_single_crate_moves_agree() {
+        for seed in 0..100 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let crane = random_crane(&mut rng);
+            let movements = generate_procedure(&mut rng, &crane, 50, 1);
+            let mut expected = crane.clone();
+            expected.run(&movements, &CrateMover9000).unwrap();
+            for name in MODELS {
+                let mut moved = crane.clone();
+                moved
+                    .run(&movements, crane_model(name).unwrap().as_ref())
+                    .unwrap();
+                assert_eq!(moved, expected, "seed {seed}, model {name}");
+            }
+        }
+    }
+
+    #[test]
+    fn prop_moves_are_reversible() {
+        for seed in 0..100 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut crane = random_crane(&mut rng);
+            for movement in generate_procedure(&mut rng, &crane.clone(), 20, 8) {
+                let back = Move {
+                    origin: movement.destiny,
+                    destiny: movement.origin,
+                    ..movement.clone()
+                };
+                for model in [&CrateMover9000 as &dyn CraneModel, &CrateMover9001] {
+                    let mut moved = crane.clone();
+                    moved.apply(model, &movement).unwrap();
+                    moved.apply(model, &back).unwrap();
+                    assert_eq!(moved, crane, "seed {seed}, {movement}");
+                }
+
+                for name in MODELS {
+                    let model = crane_model(name).unwrap();
+                    let mut moved = crane.clone();
+                    moved.apply(model.as_ref(), &movement).unwrap();
+                    moved.unapply(model.as_ref(), &movement).unwrap();
+                    assert_eq!(moved, crane, "seed {seed}, model {name}, {movement}");
+                }
+
+                crane.apply(&CrateMover9001, &movement).unwrap();
+            }
+        }
     }
 }
@@ -1,21 +1,686 @@
 use anyhow::Result;
-use std::collections::HashSet;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Write},
+    hash::Hash,
+    io::{self, Read},
+    time::Instant,
+};
 
-fn start_of_packer_marker(s: &str, d: usize) -> Option<usize> {
+/// Finds the end of the first window of `d` different bytes in linear time.
+/// Instead of building a set for every window it remembers where each byte
+/// value was last seen, which is where a run of distinct bytes has to restart.
+struct MarkerDetector {
+    size: usize,
+    /// One past the index each byte value was last seen at, 0 if never.
+    last_seen: [usize; 256],
+    run_start: usize,
+    position: usize,
+}
+
+impl MarkerDetector {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            last_seen: [0; 256],
+            run_start: 0,
+            position: 0,
+        }
+    }
+
+    /// Feeds the next byte, returning whether the last `size` bytes fed are
+    /// now all different.
+    fn push(&mut self, byte: u8) -> bool {
+        self.run_start = self.run_start.max(self.last_seen[byte as usize]);
+        self.position += 1;
+        self.last_seen[byte as usize] = self.position;
+        self.position - self.run_start >= self.size
+    }
+
+    /// Forgets the bytes fed so far, so the next marker cannot overlap the
+    /// last one.
+    fn restart(&mut self) {
+        self.run_start = self.position;
+    }
+
+    /// Feeds a chunk of bytes, returning the offset the marker ends at if it
+    /// is within the chunk. Bytes after the marker are left unread.
+    fn feed(&mut self, chunk: &[u8]) -> Option<usize> {
+        if self.size == 0 {
+            return Some(0);
+        }
+        chunk
+            .iter()
+            .position(|byte| self.push(*byte))
+            .map(|_| self.position)
+    }
+}
+
+/// Every offset at which a window of distinct bytes ends, as `(size, offset)`
+/// pairs, for several window sizes in a single pass over the bytes. Markers
+/// ending at the same offset come out in the order their sizes were given.
+struct Markers<I> {
+    bytes: I,
+    detectors: Vec<MarkerDetector>,
+    non_overlapping: bool,
+    pending: VecDeque<(usize, usize)>,
+}
+
+impl<I: Iterator<Item = u8>> Markers<I> {
+    /// With `non_overlapping` each size starts looking for its next marker
+    /// right after the end of the last one, instead of one byte later.
+    fn new(bytes: I, sizes: &[usize], non_overlapping: bool) -> Self {
+        assert!(
+            sizes.iter().all(|size| *size > 0),
+            "Window sizes must be positive"
+        );
+        Self {
+            bytes,
+            detectors: sizes
+                .iter()
+                .map(|size| MarkerDetector::new(*size))
+                .collect(),
+            non_overlapping,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for Markers<I> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            let byte = self.bytes.next()?;
+            for detector in self.detectors.iter_mut() {
+                if detector.push(byte) {
+                    self.pending.push_back((detector.size, detector.position));
+                    if self.non_overlapping {
+                        detector.restart();
+                    }
+                }
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+fn markers<'a>(
+    data: &'a [u8],
+    sizes: &[usize],
+    non_overlapping: bool,
+) -> Markers<impl Iterator<Item = u8> + 'a> {
+    Markers::new(data.iter().copied(), sizes, non_overlapping)
+}
+
+/// A property of a window of bytes that is kept up to date while the window
+/// slides over the stream, so checking it doesn't cost a pass over the window.
+trait WindowPredicate {
+    fn size(&self) -> usize;
+    /// `leaving` is the byte dropping out of the window, once it is full. It
+    /// is taken out after `entering` is added.
+    fn slide(&mut self, entering: u8, leaving: Option<u8>);
+    fn matches(&self, window: &[u8]) -> bool;
+}
+
+/// No more than `k` bytes of the window repeat an earlier one. With `k = 0`
+/// this is the marker from parts one and two.
+struct AtMostRepeats {
+    size: usize,
+    k: usize,
+    counts: [usize; 256],
+    distinct: usize,
+}
+
+impl AtMostRepeats {
+    fn new(size: usize, k: usize) -> Self {
+        Self {
+            size,
+            k,
+            counts: [0; 256],
+            distinct: 0,
+        }
+    }
+}
+
+impl WindowPredicate for AtMostRepeats {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn slide(&mut self, entering: u8, leaving: Option<u8>) {
+        self.counts[entering as usize] += 1;
+        if self.counts[entering as usize] == 1 {
+            self.distinct += 1;
+        }
+        if let Some(leaving) = leaving {
+            self.counts[leaving as usize] -= 1;
+            if self.counts[leaving as usize] == 0 {
+                self.distinct -= 1;
+            }
+        }
+    }
+
+    fn matches(&self, _window: &[u8]) -> bool {
+        self.size - self.distinct <= self.k
+    }
+}
+
+/// Every byte of a set appears somewhere in the window.
+struct ContainsAll {
+    size: usize,
+    wanted: [bool; 256],
+    counts: [usize; 256],
+    missing: usize,
+}
+
+impl ContainsAll {
+    fn new(size: usize, set: &[u8]) -> Self {
+        let mut wanted = [false; 256];
+        for &byte in set {
+            wanted[byte as usize] = true;
+        }
+        Self {
+            size,
+            wanted,
+            counts: [0; 256],
+            missing: wanted.iter().filter(|wanted| **wanted).count(),
+        }
+    }
+}
+
+impl WindowPredicate for ContainsAll {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn slide(&mut self, entering: u8, leaving: Option<u8>) {
+        if self.wanted[entering as usize] {
+            self.counts[entering as usize] += 1;
+            if self.counts[entering as usize] == 1 {
+                self.missing -= 1;
+            }
+        }
+        if let Some(leaving) = leaving.filter(|byte| self.wanted[*byte as usize]) {
+            self.counts[leaving as usize] -= 1;
+            if self.counts[leaving as usize] == 0 {
+                self.missing += 1;
+            }
+        }
+    }
+
+    fn matches(&self, _window: &[u8]) -> bool {
+        self.missing == 0
+    }
+}
+
+const HASH_BASE: u64 = 257;
+
+/// Polynomial hash of a window, the one [HashEquals] rolls along the stream.
+fn window_hash(window: &[u8]) -> u64 {
+    window.iter().fold(0, |hash, &byte| {
+        hash.wrapping_mul(HASH_BASE).wrapping_add(byte as u64)
+    })
+}
+
+/// The rolling hash of the window equals `target`.
+struct HashEquals {
+    size: usize,
+    target: u64,
+    hash: u64,
+    /// `HASH_BASE` to the window size, the weight of the byte leaving.
+    power: u64,
+}
+
+impl HashEquals {
+    fn new(size: usize, target: u64) -> Self {
+        Self {
+            size,
+            target,
+            hash: 0,
+            power: HASH_BASE.wrapping_pow(size as u32),
+        }
+    }
+}
+
+impl WindowPredicate for HashEquals {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn slide(&mut self, entering: u8, leaving: Option<u8>) {
+        self.hash = self
+            .hash
+            .wrapping_mul(HASH_BASE)
+            .wrapping_add(entering as u64);
+        if let Some(leaving) = leaving {
+            self.hash = self
+                .hash
+                .wrapping_sub((leaving as u64).wrapping_mul(self.power));
+        }
+    }
+
+    fn matches(&self, _window: &[u8]) -> bool {
+        self.hash == self.target
+    }
+}
+
+/// Any check on the window contents, for predicates that can't be updated
+/// incrementally. It costs a pass over the window at every offset.
+struct AnyWindow<F> {
+    size: usize,
+    check: F,
+}
+
+impl<F: Fn(&[u8]) -> bool> WindowPredicate for AnyWindow<F> {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn slide(&mut self, _entering: u8, _leaving: Option<u8>) {}
+
+    fn matches(&self, window: &[u8]) -> bool {
+        (self.check)(window)
+    }
+}
+
+/// Offsets right after every full window matching the predicate.
+fn scan_windows<'a, P: WindowPredicate + ?Sized>(
+    data: &'a [u8],
+    predicate: &'a mut P,
+) -> impl Iterator<Item = usize> + 'a {
+    let size = predicate.size();
+    data.iter().enumerate().filter_map(move |(i, &byte)| {
+        let leaving = i.checked_sub(size).map(|j| data[j]);
+        predicate.slide(byte, leaving);
+        let end = i + 1;
+        (end >= size && predicate.matches(&data[end - size..end])).then_some(end)
+    })
+}
+
+/// Parses one of `repeats:SIZE:K`, `contains:SIZE:SET`, `hash:SIZE:VALUE`,
+/// `equals:TEXT` or `palindrome:SIZE`.
+fn parse_predicate(spec: &str) -> Result<Box<dyn WindowPredicate>> {
+    let parts = spec.split(':').collect::<Vec<_>>();
+    Ok(match parts[..] {
+        ["repeats", size, k] => Box::new(AtMostRepeats::new(size.parse()?, k.parse()?)),
+        ["contains", size, set] => Box::new(ContainsAll::new(size.parse()?, set.as_bytes())),
+        ["hash", size, value] => Box::new(HashEquals::new(size.parse()?, value.parse()?)),
+        ["equals", text] => Box::new(HashEquals::new(text.len(), window_hash(text.as_bytes()))),
+        ["palindrome", size] => Box::new(AnyWindow {
+            size: size.parse()?,
+            check: |window: &[u8]| window.iter().eq(window.iter().rev()),
+        }),
+        _ => return Err(anyhow::anyhow!("Unknown window predicate {spec}")),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FrameKind {
+    Packet,
+    Message,
+}
+
+impl FrameKind {
+    fn marker_size(self) -> usize {
+        match self {
+            FrameKind::Packet => 4,
+            FrameKind::Message => 14,
+        }
+    }
+}
+
+/// The payload that follows a marker, up to the start of the next marker of
+/// the same kind or the end of the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Frame {
+    kind: FrameKind,
+    offset: usize,
+    length: usize,
+}
+
+impl Frame {
+    fn payload<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.offset..self.offset + self.length]
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            FrameKind::Packet => "packet",
+            FrameKind::Message => "message",
+        };
+        write!(f, "{kind} at {} ({} bytes)", self.offset, self.length)
+    }
+}
 
-    if s.len() < d {
-        return None;
+/// Splits a datastream into packets and messages. Markers of one kind never
+/// overlap each other, so a marker can't show up inside its own payload.
+fn decode(data: &[u8]) -> Vec<Frame> {
+    let kinds = [FrameKind::Packet, FrameKind::Message];
+    let sizes = kinds.map(FrameKind::marker_size);
+    let mut starts = [vec![], vec![]];
+    for (size, offset) in markers(data, &sizes, true) {
+        starts[sizes.iter().position(|s| *s == size).unwrap()].push(offset);
     }
 
-    for i in d..s.len() { if s[(i-d)..i].chars().collect::<HashSet<_>>().len() == d {
-            return Some(i);
+    let mut frames = vec![];
+    for (kind, starts) in kinds.into_iter().zip(starts) {
+        for (i, &offset) in starts.iter().enumerate() {
+            let end = starts
+                .get(i + 1)
+                .map_or(data.len(), |next| next - kind.marker_size());
+            frames.push(Frame {
+                kind,
+                offset,
+                length: end - offset,
+            });
+        }
+    }
+    frames.sort_by_key(|frame| (frame.offset, frame.kind));
+    frames
+}
+
+/// Classic 16 bytes per line hex dump, with offsets counted from `base`.
+fn hex_dump(data: &[u8], base: usize) -> String {
+    let mut dump = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        write!(dump, "{:08x} ", base + 16 * i).unwrap();
+        for column in 0..16 {
+            if column == 8 {
+                dump.push(' ');
+            }
+            match line.get(column) {
+                Some(byte) => write!(dump, " {byte:02x}").unwrap(),
+                None => dump.push_str("   "),
+            }
+        }
+        let ascii = line
+            .iter()
+            .map(|&byte| match byte {
+                b' '..=b'~' => byte as char,
+                _ => '.',
+            })
+            .collect::<String>();
+        writeln!(dump, "  |{ascii}|").unwrap();
+    }
+    dump
+}
+
+/// Runs one detector per window size over a stream given as chunks of bytes,
+/// calling `found` with the size and offset of each marker as soon as it shows
+/// up. Stops pulling chunks once every marker has been found.
+fn scan_chunks<C: AsRef<[u8]>>(
+    chunks: impl IntoIterator<Item = C>,
+    sizes: &[usize],
+    mut found: impl FnMut(usize, usize),
+) {
+    let mut detectors = sizes
+        .iter()
+        .map(|size| Some(MarkerDetector::new(*size)))
+        .collect::<Vec<_>>();
+    let mut chunks = chunks.into_iter();
+
+    while detectors.iter().any(Option::is_some) {
+        let Some(chunk) = chunks.next() else {
+            break;
+        };
+        let mut markers = vec![];
+        for slot in detectors.iter_mut() {
+            if let Some(detector) = slot {
+                if let Some(offset) = detector.feed(chunk.as_ref()) {
+                    markers.push((offset, detector.size));
+                    *slot = None;
+                }
+            }
+        }
+        markers.sort();
+        markers
+            .into_iter()
+            .for_each(|(offset, size)| found(size, offset));
+    }
+}
+
+/// Same as `scan_chunks` reading from `reader` a buffer at a time, so large
+/// captures never need to be in memory.
+fn scan_reader(
+    mut reader: impl Read,
+    sizes: &[usize],
+    found: impl FnMut(usize, usize),
+) -> io::Result<()> {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut error = None;
+    let chunks = std::iter::from_fn(|| loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return None,
+            Ok(read) => return Some(buffer[..read].to_vec()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                error = Some(err);
+                return None;
+            }
+        }
+    });
+    scan_chunks(chunks, sizes, found);
+    error.map_or(Ok(()), Err)
+}
+
+/// The marker of parts one and two is the window scanner looking for `d`
+/// bytes without a single repeat.
+fn start_of_packer_marker_bytes(data: &[u8], d: usize) -> Option<usize> {
+    if d == 0 {
+        return Some(0);
+    }
+
+    scan_windows(data, &mut AtMostRepeats::new(d, 0)).next()
+}
+
+/// Same as `MarkerDetector` for any kind of symbol, keeping the last seen
+/// positions in a map.
+fn find_marker<T: Eq + Hash>(items: impl IntoIterator<Item = T>, d: usize) -> Option<usize> {
+    if d == 0 {
+        return Some(0);
+    }
+
+    let mut last_seen = HashMap::new();
+    let mut run_start = 0;
+    for (i, item) in items.into_iter().enumerate() {
+        if let Some(seen) = last_seen.insert(item, i + 1) {
+            run_start = run_start.max(seen);
+        }
+        if i + 1 - run_start >= d {
+            return Some(i + 1);
         }
     }
 
     None
 }
 
+/// A set per window, the straightforward O(n * d) version.
+fn start_of_packer_marker_hashset(data: &[u8], d: usize) -> Option<usize> {
+    if d == 0 {
+        return Some(0);
+    }
+
+    data.windows(d)
+        .position(|window| window.iter().collect::<HashSet<_>>().len() == d)
+        .map(|i| i + d)
+}
+
+/// Only for lowercase letters: each letter is a bit, and XOR-ing a letter in
+/// when it enters the window and out when it leaves keeps the bits of the
+/// letters seen an odd number of times. All `d` letters are distinct exactly
+/// when `d` bits are set.
+fn start_of_packer_marker_bitmask(data: &[u8], d: usize) -> Option<usize> {
+    if d == 0 {
+        return Some(0);
+    }
+
+    let bit = |byte: u8| 1u32 << (byte - b'a');
+    let mut mask = 0;
+    for (i, &byte) in data.iter().enumerate() {
+        mask ^= bit(byte);
+        if i >= d {
+            mask ^= bit(data[i - d]);
+        }
+        if mask.count_ones() as usize == d {
+            return Some(i + 1);
+        }
+    }
+
+    None
+}
+
+/// Not part of the datastream, a file holding one usually ends with them.
+const LINE_TERMINATORS: [char; 2] = ['\n', '\r'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    HashSet,
+    Counter,
+    Bitmask,
+    Chars,
+}
+
+impl Backend {
+    /// The fastest backend that can handle every character of the input,
+    /// not counting the line terminators it may end with.
+    fn select(s: &str) -> Self {
+        let s = s.trim_end_matches(LINE_TERMINATORS);
+        if s.bytes().all(|byte| byte.is_ascii_lowercase()) {
+            Backend::Bitmask
+        } else if s.is_ascii() {
+            Backend::Counter
+        } else {
+            Backend::Chars
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Backend::HashSet => "hash set",
+            Backend::Counter => "counter",
+            Backend::Bitmask => "bitmask",
+            Backend::Chars => "chars",
+        }
+    }
+
+    fn find(self, s: &str, d: usize) -> Option<usize> {
+        let s = s.trim_end_matches(LINE_TERMINATORS);
+        match self {
+            Backend::HashSet => start_of_packer_marker_hashset(s.as_bytes(), d),
+            Backend::Counter => start_of_packer_marker_bytes(s.as_bytes(), d),
+            Backend::Bitmask => start_of_packer_marker_bitmask(s.as_bytes(), d),
+            Backend::Chars => find_marker(s.chars(), d),
+        }
+    }
+}
+
+/// Number of characters read when the first `d` distinct characters in a row
+/// have been seen, with the backend picked from the alphabet of the input.
+fn start_of_packer_marker(s: &str, d: usize) -> Option<usize> {
+    Backend::select(s).find(s, d)
+}
+
+/// Worst case stream for the scanners: `len` lowercase letters where the
+/// only marker of size `d` (at least 2) is the last `d` letters.
+fn generate_stream(rng: &mut impl Rng, len: usize, d: usize) -> String {
+    let letter = |i: u8| (b'a' + i) as char;
+    let body = (0..len.saturating_sub(d))
+        .map(|_| letter(rng.gen_range(0..d as u8 - 1)))
+        .collect::<String>();
+    body + &(0..d as u8).map(letter).collect::<String>()
+}
+
+fn bench(out: &mut impl io::Write) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(6);
+    for d in [4, 14] {
+        for len in [100_000, 1_000_000, 4_000_000] {
+            let stream = generate_stream(&mut rng, len, d);
+            for backend in [Backend::HashSet, Backend::Counter, Backend::Bitmask] {
+                let start = Instant::now();
+                let found = backend.find(&stream, d);
+                let elapsed = start.elapsed();
+                assert_eq!(found, Some(stream.len()));
+                writeln!(
+                    out,
+                    "d = {d:>2}, {len:>7} bytes, {:<8}: {:>8.1?} ({:.2} ns/byte)",
+                    backend.name(),
+                    elapsed,
+                    elapsed.as_nanos() as f64 / len as f64
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    if let [_, command] = &args[..] {
+        if command == "bench" {
+            return bench(&mut io::stdout());
+        }
+    }
+
+    if let [_, command, path, options @ ..] = &args[..] {
+        if command == "markers" {
+            let non_overlapping = match options {
+                [] => false,
+                [option] if option == "--non-overlapping" => true,
+                _ => return Err(anyhow::anyhow!("Usage: markers FILE [--non-overlapping]")),
+            };
+            let data = std::fs::read(path)?;
+            for (size, offset) in markers(&data, &[4, 14], non_overlapping) {
+                let kind = if size == 4 { "packet" } else { "message" };
+                println!("{kind} {offset}");
+            }
+            return Ok(());
+        }
+        if command == "windows" {
+            let [spec] = options else {
+                return Err(anyhow::anyhow!("Usage: windows FILE PREDICATE"));
+            };
+            let mut predicate = parse_predicate(spec)?;
+            let data = std::fs::read(path)?;
+            for end in scan_windows(&data, &mut *predicate) {
+                println!("{end}");
+            }
+            return Ok(());
+        }
+        if command == "decode" {
+            let dump = match options {
+                [] => false,
+                [option] if option == "--dump" => true,
+                _ => return Err(anyhow::anyhow!("Usage: decode FILE [--dump]")),
+            };
+            let data = std::fs::read(path)?;
+            for frame in decode(&data) {
+                println!("{frame}");
+                if dump {
+                    print!("{}", hex_dump(frame.payload(&data), frame.offset));
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    if let [_, command, path] = &args[..] {
+        if command == "scan" {
+            let report = |size, offset| match size {
+                4 => println!("Start-of-packet marker after {offset} bytes"),
+                14 => println!("Start-of-message marker after {offset} bytes"),
+                _ => unreachable!(),
+            };
+            match path.as_str() {
+                "-" => scan_reader(io::stdin().lock(), &[4, 14], report)?,
+                path => scan_reader(std::fs::File::open(path)?, &[4, 14], report)?,
+            }
+            return Ok(());
+        }
+    }
 
     let solution_1 = solve_part_one();
 
@@ -44,7 +709,20 @@ fn solve_part_two() -> Option<usize> {
 
 #[cfg(test)]
 mod tests {
-    use crate::start_of_packer_marker;
+    use crate::{
+        decode, generate_stream, hex_dump, markers, parse_predicate, scan_chunks, scan_reader,
+        scan_windows, start_of_packer_marker, start_of_packer_marker_bytes, window_hash,
+        AtMostRepeats, Backend, ContainsAll, Frame, FrameKind, HashEquals, WindowPredicate,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::{collections::HashSet, io::Read};
+
+    /// The original quadratic implementation, fixed to also look at the
+    /// window ending at the last character.
+    fn naive_marker(s: &str, d: usize) -> Option<usize> {
+        let chars = s.chars().collect::<Vec<_>>();
+        (d..=chars.len()).find(|&i| chars[(i - d)..i].iter().collect::<HashSet<_>>().len() == d)
+    }
 
 
     #[test]
@@ -75,4 +753,284 @@ mod tests {
         assert_eq!(Some(26), result);
     }
 
+    #[test]
+    fn test_linear_matches_naive() {
+        let mut rng = StdRng::seed_from_u64(6);
+        for _ in 0..500 {
+            let alphabet = rng.gen_range(1..=20);
+            let len = rng.gen_range(0..60);
+            let s = (0..len)
+                .map(|_| (b'a' + rng.gen_range(0..alphabet)) as char)
+                .collect::<String>();
+            for d in [0, 1, 2, 4, 7, 14] {
+                assert_eq!(
+                    start_of_packer_marker(&s, d),
+                    naive_marker(&s, d),
+                    "{s} {d}"
+                );
+                assert_eq!(
+                    start_of_packer_marker_bytes(s.as_bytes(), d),
+                    naive_marker(&s, d)
+                );
+            }
+        }
+
+        let input = include_str!("./day6.input");
+        for d in [4, 14] {
+            assert_eq!(start_of_packer_marker(input, d), naive_marker(input, d));
+        }
+    }
+
+    #[test]
+    fn test_non_ascii() {
+        assert_eq!(start_of_packer_marker("ééàéçü", 3), Some(5));
+        assert_eq!(start_of_packer_marker("日本日本語", 3), Some(5));
+        assert_eq!(start_of_packer_marker("ééé", 2), None);
+        assert_eq!(
+            start_of_packer_marker_bytes(&[0, 255, 0, 128, 7], 3),
+            Some(4)
+        );
+        assert_eq!(start_of_packer_marker("abc", 3), Some(3));
+    }
+
+    #[test]
+    fn test_streaming_scan() {
+        let input = include_str!("./day6.input").as_bytes();
+        let expected = [(4, 1480), (14, 2746)];
+
+        for chunk_size in [1, 3, 14, 100, 4096] {
+            let mut found = vec![];
+            scan_chunks(input.chunks(chunk_size), &[4, 14], |size, offset| {
+                found.push((size, offset))
+            });
+            assert_eq!(found, expected);
+        }
+
+        let mut found = vec![];
+        scan_reader(input, &[14, 4], |size, offset| found.push((size, offset))).unwrap();
+        assert_eq!(found, expected);
+
+        let mut found = vec![];
+        scan_chunks(["abc", "abc"], &[0, 3, 4], |size, offset| {
+            found.push((size, offset))
+        });
+        assert_eq!(found, vec![(0, 0), (3, 3)]);
+    }
+
+    #[test]
+    fn test_streaming_stops_reading() {
+        struct Tracked<'a> {
+            data: &'a [u8],
+            read: usize,
+        }
+
+        impl Read for Tracked<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(16).min(self.data.len() - self.read);
+                buf[..n].copy_from_slice(&self.data[self.read..self.read + n]);
+                self.read += n;
+                Ok(n)
+            }
+        }
+
+        let data = "aaaaaaaaaaaaaaaaaaaabcd".repeat(1000);
+        let mut reader = Tracked {
+            data: data.as_bytes(),
+            read: 0,
+        };
+        let mut found = vec![];
+        scan_reader(&mut reader, &[4], |size, offset| found.push((size, offset))).unwrap();
+        assert_eq!(found, vec![(4, 23)]);
+        assert_eq!(reader.read, 32);
+    }
+
+    #[test]
+    fn test_every_marker() {
+        let found = markers(b"abcabcc", &[3], false).collect::<Vec<_>>();
+        assert_eq!(found, vec![(3, 3), (3, 4), (3, 5), (3, 6)]);
+        let found = markers(b"abcabcc", &[3], true).collect::<Vec<_>>();
+        assert_eq!(found, vec![(3, 3), (3, 6)]);
+        let found = markers(b"abcd", &[2, 3], false).collect::<Vec<_>>();
+        assert_eq!(found, vec![(2, 2), (2, 3), (3, 3), (2, 4), (3, 4)]);
+
+        let mut rng = StdRng::seed_from_u64(43);
+        for _ in 0..200 {
+            let data = (0..rng.gen_range(0..80))
+                .map(|_| b'a' + rng.gen_range(0..6))
+                .collect::<Vec<_>>();
+            let s = String::from_utf8(data.clone()).unwrap();
+            let distinct = |start: usize, end: usize| {
+                data[start..end].iter().collect::<HashSet<_>>().len() == end - start
+            };
+            for d in [1, 2, 4] {
+                let overlapping = (d..=data.len())
+                    .filter(|&i| distinct(i - d, i))
+                    .collect::<Vec<_>>();
+                let found = markers(&data, &[d], false).map(|(_, offset)| offset);
+                assert_eq!(found.collect::<Vec<_>>(), overlapping, "{s}");
+                assert_eq!(overlapping.first().copied(), naive_marker(&s, d));
+
+                let mut non_overlapping = vec![];
+                for &end in &overlapping {
+                    if non_overlapping.last().is_none_or(|last| end - d >= *last) {
+                        non_overlapping.push(end);
+                    }
+                }
+                let found = markers(&data, &[d], true).map(|(_, offset)| offset);
+                assert_eq!(found.collect::<Vec<_>>(), non_overlapping, "{s}");
+            }
+
+            let both = markers(&data, &[4, 14], false).collect::<Vec<_>>();
+            assert!(both.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+            assert_eq!(
+                both.iter().filter(|(size, _)| *size == 4).count(),
+                markers(&data, &[4], false).count()
+            );
+        }
+
+        let input = include_str!("./day6.input").as_bytes();
+        let first = |size| markers(input, &[4, 14], false).find(|marker| marker.0 == size);
+        assert_eq!(first(4), Some((4, 1480)));
+        assert_eq!(first(14), Some((14, 2746)));
+    }
+
+    #[test]
+    fn test_decode() {
+        let data = b"aabcdxyyefghzzzz";
+        let frames = decode(data);
+        let packet = |offset, length| Frame {
+            kind: FrameKind::Packet,
+            offset,
+            length,
+        };
+        assert_eq!(frames, vec![packet(5, 2), packet(11, 5)]);
+        assert_eq!(frames[0].payload(data), b"xy");
+        assert_eq!(frames[0].to_string(), "packet at 5 (2 bytes)");
+
+        let input = include_str!("./day6.input").as_bytes();
+        let frames = decode(input);
+        assert_eq!(frames[0].offset, 1480);
+        let first_message = frames.iter().find(|f| f.kind == FrameKind::Message);
+        assert_eq!(first_message.unwrap().offset, 2746);
+        for kind in [FrameKind::Packet, FrameKind::Message] {
+            let frames = frames.iter().filter(|f| f.kind == kind).collect::<Vec<_>>();
+            let covered = frames
+                .iter()
+                .map(|f| f.length + kind.marker_size())
+                .sum::<usize>();
+            assert_eq!(
+                covered,
+                input.len() - (frames[0].offset - kind.marker_size())
+            );
+        }
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        let dump = hex_dump(b"mjqjpqmgbljsphdztnvjfqwrcgsmlb\n", 0x10);
+        assert_eq!(
+            dump,
+            "00000010  6d 6a 71 6a 70 71 6d 67  62 6c 6a 73 70 68 64 7a  |mjqjpqmgbljsphdz|\n\
+             00000020  74 6e 76 6a 66 71 77 72  63 67 73 6d 6c 62 0a     |tnvjfqwrcgsmlb.|\n"
+        );
+        assert_eq!(hex_dump(b"", 0), "");
+    }
+
+    #[test]
+    fn test_window_predicates() {
+        fn naive(data: &[u8], size: usize, check: impl Fn(&[u8]) -> bool) -> Vec<usize> {
+            (size.max(1)..=data.len())
+                .filter(|&end| check(&data[end - size..end]))
+                .collect()
+        }
+        fn scan(data: &[u8], mut predicate: impl WindowPredicate) -> Vec<usize> {
+            scan_windows(data, &mut predicate).collect()
+        }
+
+        let mut rng = StdRng::seed_from_u64(45);
+        for _ in 0..200 {
+            let data = (0..rng.gen_range(0..60))
+                .map(|_| b'a' + rng.gen_range(0..5))
+                .collect::<Vec<_>>();
+            for size in 0..7 {
+                for k in 0..3 {
+                    let expected = naive(&data, size, |window| {
+                        size - window.iter().collect::<HashSet<_>>().len() <= k
+                    });
+                    assert_eq!(scan(&data, AtMostRepeats::new(size, k)), expected);
+                }
+
+                let expected = naive(&data, size, |window| {
+                    window.contains(&b'a') && window.contains(&b'c')
+                });
+                assert_eq!(scan(&data, ContainsAll::new(size, b"aca")), expected);
+
+                let target = window_hash(&data[..size.min(data.len())]);
+                let expected = naive(&data, size, |window| window_hash(window) == target);
+                assert_eq!(scan(&data, HashEquals::new(size, target)), expected);
+            }
+        }
+
+        let data = b"abcabcbacb";
+        let mut equals = parse_predicate("equals:bca").unwrap();
+        assert_eq!(
+            scan_windows(data, &mut *equals).collect::<Vec<_>>(),
+            vec![4]
+        );
+        let mut palindrome = parse_predicate("palindrome:3").unwrap();
+        let found = scan_windows(data, &mut *palindrome).collect::<Vec<_>>();
+        assert_eq!(found, vec![7]);
+        assert!(parse_predicate("repeats:4").is_err());
+        assert!(parse_predicate("contains:x:ab").is_err());
+
+        let input = include_str!("./day6.input").as_bytes();
+        let mut part_one = parse_predicate("repeats:4:0").unwrap();
+        assert_eq!(scan_windows(input, &mut *part_one).next(), Some(1480));
+        let mut part_two = parse_predicate("repeats:14:0").unwrap();
+        assert_eq!(scan_windows(input, &mut *part_two).next(), Some(2746));
+    }
+
+    #[test]
+    fn test_backends() {
+        assert_eq!(Backend::select("mjqjpqmgbljsphdz"), Backend::Bitmask);
+        assert_eq!(Backend::select("mjqjpq\nmgbljsphdz"), Backend::Counter);
+        assert_eq!(Backend::select("mjqjpqmgbljsphdzé"), Backend::Chars);
+
+        let backends = [
+            Backend::HashSet,
+            Backend::Counter,
+            Backend::Bitmask,
+            Backend::Chars,
+        ];
+        let mut rng = StdRng::seed_from_u64(46);
+        for _ in 0..300 {
+            let letters = rng.gen_range(1..=26);
+            let s = (0..rng.gen_range(0..100))
+                .map(|_| (b'a' + rng.gen_range(0..letters)) as char)
+                .collect::<String>();
+            for d in [1, 2, 4, 14, 26, 27] {
+                for backend in backends {
+                    assert_eq!(backend.find(&s, d), naive_marker(&s, d), "{backend:?} {s}");
+                }
+            }
+        }
+
+        for d in [2, 4, 14] {
+            let stream = generate_stream(&mut rng, 500, d);
+            assert_eq!(stream.len(), 500);
+            assert_eq!(naive_marker(&stream, d), Some(500));
+        }
+
+        assert_eq!(Backend::select("abc\r\n"), Backend::Bitmask);
+        assert_eq!(Backend::Bitmask.find("abc\n", 3), Some(3));
+        assert_eq!(Backend::Bitmask.find("abc\n", 4), None);
+
+        let input = include_str!("./day6.input");
+        assert!(input.ends_with('\n'));
+        assert_eq!(Backend::select(input), Backend::Bitmask);
+        for backend in backends {
+            assert_eq!(backend.find(input, 4), Some(1480));
+            assert_eq!(backend.find(input, 14), Some(2746));
+        }
+    }
 }
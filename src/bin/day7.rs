@@ -1,16 +1,27 @@
 use anyhow::{anyhow, Result};
+use itertools::{EitherOrBoth, Itertools};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    io::{BufRead, BufReader},
     rc::{Rc, Weak},
     str::FromStr,
 };
 
 #[derive(Debug, PartialEq)]
 enum Command {
+    CdRoot,
     CdDown(String),
     CdUp,
     Ls,
+    LsPath(String),
+    Pwd,
+    Mkdir(String),
+    Rm(String),
+    Touch { size: usize, name: String },
 }
 
 impl FromStr for Command {
@@ -18,9 +29,18 @@ impl FromStr for Command {
 
     fn from_str(s: &str) -> Result<Self> {
         return match s.split(" ").collect::<Vec<_>>()[..] {
+            [_, "cd", "/"] => Ok(Command::CdRoot),
             [_, "cd", ".."] => Ok(Command::CdUp),
             [_, "cd", s] => Ok(Command::CdDown(String::from_str(s)?)),
             [_, "ls"] => Ok(Command::Ls),
+            [_, "ls", path] => Ok(Command::LsPath(path.to_string())),
+            [_, "pwd"] => Ok(Command::Pwd),
+            [_, "mkdir", path] => Ok(Command::Mkdir(path.to_string())),
+            [_, "rm", path] => Ok(Command::Rm(path.to_string())),
+            [_, "touch", size, name] => Ok(Command::Touch {
+                size: size.parse()?,
+                name: name.to_string(),
+            }),
             _ => Err(anyhow!("Invalid Command input {s}")),
         };
     }
@@ -116,45 +136,916 @@ impl FileSystem {
 
         go(&self.root.borrow(), &"/".to_string(), vec![])
     }
+
+    /// Flattens the tree into a map from absolute path to entry kind and size.
+    /// Directory sizes are the total size of everything below them.
+    fn entries(&self) -> BTreeMap<String, (EntryKind, usize)> {
+        fn go(
+            node: &FileSystemNode,
+            path: &str,
+            result: &mut BTreeMap<String, (EntryKind, usize)>,
+        ) -> usize {
+            let mut size = 0_usize;
+            for file in &node.files {
+                if let File::RegularFile {
+                    size: file_size,
+                    name,
+                } = file
+                {
+                    result.insert(join_path(path, name), (EntryKind::RegularFile, *file_size));
+                    size += file_size;
+                }
+            }
+
+            for (dir_name, dir) in &node.dirs {
+                size += go(&dir.borrow(), &join_path(path, dir_name), result);
+            }
+
+            result.insert(path.to_owned(), (EntryKind::Directory, size));
+            size
+        }
+
+        let mut result = BTreeMap::new();
+        go(&self.root.borrow(), "/", &mut result);
+        result
+    }
+}
+
+/// Version of the on-disk snapshot layout. Bump it whenever
+/// `FileSystemSnapshot` or `DirectorySnapshot` change shape.
+const SNAPSHOT_VERSION: u32 = 2;
+
+/// Owned mirror of the `FileSystemNode` tree used as the serialized schema.
+/// Directories are kept in a `BTreeMap` so the output is deterministic.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct DirectorySnapshot {
+    dirs: BTreeMap<String, DirectorySnapshot>,
+    files: Vec<FileSnapshot>,
+}
+
+/// Only regular files are listed, subdirectories live in `dirs`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct FileSnapshot {
+    name: String,
+    size: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct FileSystemSnapshot {
+    version: u32,
+    root: DirectorySnapshot,
 }
 
+impl FileSystem {
+    fn to_snapshot(&self) -> FileSystemSnapshot {
+        fn go(node: &FileSystemNode) -> DirectorySnapshot {
+            DirectorySnapshot {
+                dirs: node
+                    .dirs
+                    .iter()
+                    .map(|(name, dir)| (name.clone(), go(&dir.borrow())))
+                    .collect(),
+                files: node
+                    .files
+                    .iter()
+                    .filter_map(|file| match file {
+                        File::RegularFile { size, name } => Some(FileSnapshot {
+                            name: name.clone(),
+                            size: *size,
+                        }),
+                        File::Directory { .. } => None,
+                    })
+                    .collect(),
+            }
+        }
+
+        FileSystemSnapshot {
+            version: SNAPSHOT_VERSION,
+            root: go(&self.root.borrow()),
+        }
+    }
+
+    fn from_snapshot(snapshot: FileSystemSnapshot) -> Result<Self> {
+        fn go(snapshot: DirectorySnapshot, node: &Dir) {
+            node.borrow_mut().files = snapshot
+                .files
+                .into_iter()
+                .map(|FileSnapshot { name, size }| File::RegularFile { size, name })
+                .collect();
+            for (name, dir_snapshot) in snapshot.dirs {
+                let dir = Rc::new(RefCell::new(FileSystemNode::new(Rc::clone(node))));
+                go(dir_snapshot, &dir);
+                node.borrow_mut().dirs.insert(name, dir);
+            }
+        }
+
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(anyhow!(
+                "Unsupported snapshot version {} (expected {SNAPSHOT_VERSION})",
+                snapshot.version
+            ));
+        }
+
+        let file_system = FileSystem {
+            root: Rc::new(RefCell::new(FileSystemNode {
+                parent: None,
+                dirs: HashMap::new(),
+                files: vec![],
+            })),
+        };
+        go(snapshot.root, &file_system.root);
+        Ok(file_system)
+    }
+
+    fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.to_snapshot())?)
+    }
+
+    fn from_json(s: &str) -> Result<Self> {
+        Self::from_snapshot(serde_json::from_str(s)?)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.to_snapshot())?)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_snapshot(bincode::deserialize(bytes)?)
+    }
+
+    /// Writes a snapshot, choosing JSON for `.json` paths and the binary
+    /// format for anything else.
+    fn save(&self, path: &str) -> Result<()> {
+        if path.ends_with(".json") {
+            std::fs::write(path, self.to_json()?)?;
+        } else {
+            std::fs::write(path, self.to_bytes()?)?;
+        }
+        Ok(())
+    }
+
+    fn load(path: &str) -> Result<Self> {
+        if path.ends_with(".json") {
+            Self::from_json(&std::fs::read_to_string(path)?)
+        } else {
+            Self::from_bytes(&std::fs::read(path)?)
+        }
+    }
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.ends_with('/') {
+        format!("{parent}{name}")
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EntryKind {
+    RegularFile,
+    Directory,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryKind::RegularFile => write!(f, "file"),
+            EntryKind::Directory => write!(f, "dir "),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Change {
+    Added {
+        path: String,
+        kind: EntryKind,
+        size: usize,
+    },
+    Removed {
+        path: String,
+        kind: EntryKind,
+        size: usize,
+    },
+    Resized {
+        path: String,
+        kind: EntryKind,
+        old_size: usize,
+        new_size: usize,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { path, kind, size } => write!(f, "+ {kind} {path} ({size})"),
+            Change::Removed { path, kind, size } => write!(f, "- {kind} {path} ({size})"),
+            Change::Resized {
+                path,
+                kind,
+                old_size,
+                new_size,
+            } => write!(
+                f,
+                "~ {kind} {path} ({old_size} -> {new_size}, {:+})",
+                *new_size as i64 - *old_size as i64
+            ),
+        }
+    }
+}
+
+/// Compares two snapshots entry by entry. Any change below a directory also
+/// shows up as a `Resized` entry for each of its ancestors, root included.
+fn diff(old: &FileSystem, new: &FileSystem) -> Vec<Change> {
+    old.entries()
+        .into_iter()
+        .merge_join_by(new.entries(), |(a, _), (b, _)| a.cmp(b))
+        .flat_map(|entry| match entry {
+            EitherOrBoth::Left((path, (kind, size))) => vec![Change::Removed { path, kind, size }],
+            EitherOrBoth::Right((path, (kind, size))) => vec![Change::Added { path, kind, size }],
+            EitherOrBoth::Both((path, (old_kind, old_size)), (_, (new_kind, new_size))) => {
+                if old_kind != new_kind {
+                    vec![
+                        Change::Removed {
+                            path: path.clone(),
+                            kind: old_kind,
+                            size: old_size,
+                        },
+                        Change::Added {
+                            path,
+                            kind: new_kind,
+                            size: new_size,
+                        },
+                    ]
+                } else if old_size != new_size {
+                    vec![Change::Resized {
+                        path,
+                        kind: new_kind,
+                        old_size,
+                        new_size,
+                    }]
+                } else {
+                    vec![]
+                }
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+struct Warning {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+type Dir = Rc<RefCell<FileSystemNode>>;
+
 struct Session {
-    current_dir: Rc<RefCell<FileSystemNode>>,
+    root: Dir,
+    current_dir: Dir,
+    current_path: Vec<String>,
+    listing_dir: Dir,
+    awaiting_pwd: bool,
+    line: usize,
+    warnings: Vec<Warning>,
 }
 
 impl Session {
-    fn cd_up(mut self) -> Self {
-        let new_current_dir = self
-            .current_dir
-            .borrow()
-            .parent
-            .as_ref()
-            .expect("Parent should not be null")
-            .upgrade()
-            .expect("Parent should exist already");
-
-        self.current_dir = new_current_dir;
+    fn new(root: &Dir) -> Self {
+        Self {
+            root: Rc::clone(root),
+            current_dir: Rc::clone(root),
+            current_path: vec![],
+            listing_dir: Rc::clone(root),
+            awaiting_pwd: false,
+            line: 0,
+            warnings: vec![],
+        }
+    }
+
+    fn warn(mut self, message: String) -> Self {
+        self.warnings.push(Warning {
+            line: self.line,
+            message,
+        });
         self
     }
 
-    fn cd_down(mut self, dir: &str) -> Self {
-        self.current_dir = Rc::clone(
-            Rc::clone(&self.current_dir)
-                .borrow_mut()
-                .dirs
-                .entry(dir.to_string())
-                .or_insert_with(|| Rc::new(RefCell::new(FileSystemNode::new(self.current_dir)))),
-        );
+    fn pwd_string(&self) -> String {
+        format!("/{}", self.current_path.join("/"))
+    }
+
+    /// Walks `path` from the root when it is absolute, or from the current
+    /// directory otherwise. Missing directories are created when `create` is
+    /// set, otherwise the walk gives up and returns `None`.
+    fn resolve(&self, path: &str, create: bool) -> Option<(Dir, Vec<String>)> {
+        let (mut dir, mut components) = if path.starts_with('/') {
+            (Rc::clone(&self.root), vec![])
+        } else {
+            (Rc::clone(&self.current_dir), self.current_path.clone())
+        };
+
+        for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            if component == ".." {
+                let parent = dir
+                    .borrow()
+                    .parent
+                    .as_ref()
+                    .map(|parent| parent.upgrade().expect("Parent should exist already"));
+                if let Some(parent) = parent {
+                    dir = parent;
+                    components.pop();
+                }
+                continue;
+            }
+
+            let child = if create {
+                Rc::clone(
+                    dir.borrow_mut()
+                        .dirs
+                        .entry(component.to_string())
+                        .or_insert_with(|| {
+                            Rc::new(RefCell::new(FileSystemNode::new(Rc::clone(&dir))))
+                        }),
+                )
+            } else {
+                Rc::clone(dir.borrow().dirs.get(component)?)
+            };
+            dir = child;
+            components.push(component.to_string());
+        }
+
+        Some((dir, components))
+    }
+
+    /// Splits `path` into the directory holding the last component and the
+    /// component name.
+    fn resolve_parent(&self, path: &str, create: bool) -> Option<(Dir, String)> {
+        let (parent, name) = match path.trim_end_matches('/').rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((parent, name)) => (parent, name),
+            None => (".", path),
+        };
+        if name.is_empty() || name == "." || name == ".." {
+            return None;
+        }
+        let (dir, _) = self.resolve(parent, create)?;
+        Some((dir, name.to_string()))
+    }
+
+    fn cd(mut self, path: &str) -> Self {
+        let (dir, components) = self
+            .resolve(path, true)
+            .expect("Resolving with create never fails");
+        self.current_dir = dir;
+        self.current_path = components;
+        self.listing_dir = Rc::clone(&self.current_dir);
         self
     }
 
+    fn cd_root(self) -> Self {
+        self.cd("/")
+    }
+
+    fn cd_up(self) -> Self {
+        self.cd("..")
+    }
+
+    fn cd_down(self, dir: &str) -> Self {
+        self.cd(dir)
+    }
+
+    fn ls(mut self, path: Option<&str>) -> Self {
+        self.listing_dir = match path {
+            Some(path) => {
+                self.resolve(path, true)
+                    .expect("Resolving with create never fails")
+                    .0
+            }
+            None => Rc::clone(&self.current_dir),
+        };
+        self
+    }
+
+    fn pwd(mut self) -> Self {
+        self.awaiting_pwd = true;
+        self
+    }
+
+    fn check_pwd(self, printed: &str) -> Self {
+        let expected = self.pwd_string();
+        if printed == expected {
+            self
+        } else {
+            self.warn(format!(
+                "pwd printed {printed} but the session is at {expected}"
+            ))
+        }
+    }
+
+    fn mkdir(self, path: &str) -> Self {
+        let _ = self.resolve(path, true);
+        self
+    }
+
+    fn rm(self, path: &str) -> Self {
+        let removed = match self.resolve_parent(path, false) {
+            Some((dir, name)) => {
+                let mut dir = dir.borrow_mut();
+                let files_before = dir.files.len();
+                dir.files.retain(|file| !matches!(file, File::RegularFile { name: file_name, .. } if *file_name == name));
+                dir.dirs.remove(&name).is_some() || dir.files.len() != files_before
+            }
+            None => false,
+        };
+        if removed {
+            self
+        } else {
+            self.warn(format!(
+                "rm: cannot remove {path}: no such file or directory"
+            ))
+        }
+    }
+
+    fn touch(self, size: usize, path: &str) -> Self {
+        match self.resolve_parent(path, true) {
+            Some((dir, name)) => {
+                upsert_file(&dir, File::RegularFile { size, name });
+                self
+            }
+            None => self.warn(format!("touch: invalid file name {path}")),
+        }
+    }
+
     fn add_file(self, file: File) -> Self {
-        self.current_dir.borrow_mut().files.push(file);
+        match file {
+            File::Directory { name } => {
+                let _ = self
+                    .listing_dir
+                    .borrow_mut()
+                    .dirs
+                    .entry(name)
+                    .or_insert_with(|| {
+                        Rc::new(RefCell::new(FileSystemNode::new(Rc::clone(
+                            &self.listing_dir,
+                        ))))
+                    });
+            }
+            file => upsert_file(&self.listing_dir, file),
+        }
         self
     }
+
+    fn apply(mut self, line: usize, input: &str) -> Self {
+        self.line = line;
+        if std::mem::take(&mut self.awaiting_pwd) && input.starts_with('/') {
+            return self.check_pwd(input);
+        }
+
+        match input.parse::<ShellLine>() {
+            Ok(ShellLine::OutputLine(file)) => self.add_file(file),
+            Ok(ShellLine::InputLine(Command::CdRoot)) => self.cd_root(),
+            Ok(ShellLine::InputLine(Command::CdUp)) => self.cd_up(),
+            Ok(ShellLine::InputLine(Command::CdDown(dir))) => self.cd_down(&dir),
+            Ok(ShellLine::InputLine(Command::Ls)) => self.ls(None),
+            Ok(ShellLine::InputLine(Command::LsPath(path))) => self.ls(Some(&path)),
+            Ok(ShellLine::InputLine(Command::Pwd)) => self.pwd(),
+            Ok(ShellLine::InputLine(Command::Mkdir(path))) => self.mkdir(&path),
+            Ok(ShellLine::InputLine(Command::Rm(path))) => self.rm(&path),
+            Ok(ShellLine::InputLine(Command::Touch { size, name })) => self.touch(size, &name),
+            Err(err) => self.warn(format!("ignored: {err}")),
+        }
+    }
+}
+
+/// Replaces any file with the same name, so listing a directory twice or
+/// touching an existing file does not count its size twice.
+fn upsert_file(dir: &Dir, file: File) {
+    let mut dir = dir.borrow_mut();
+    if let File::RegularFile { name, .. } = &file {
+        dir.files.retain(
+            |existing| !matches!(existing, File::RegularFile { name: existing_name, .. } if existing_name == name),
+        );
+    }
+    dir.files.push(file);
+}
+
+/// Computes every directory size in a single pass over the transcript,
+/// keeping only the stack of open directories in memory. Each directory is
+/// passed to `emit` with its full path as soon as it is left, and the root is
+/// always emitted last. Unlike `run_session` this assumes every directory is
+/// entered and listed once, as the puzzle transcripts do, and warns when an
+/// open directory is listed again or a directory is entered again from the
+/// same parent, since its sizes are then counted twice. Only re-entering the
+/// child left last is noticed, unless `track_children` remembers every child
+/// left, which costs memory in the width of the tree rather than its depth.
+fn stream_sizes<R: BufRead>(
+    reader: R,
+    track_children: bool,
+    mut emit: impl FnMut(&str, usize),
+) -> Result<Vec<Warning>> {
+    struct OpenDir {
+        name: String,
+        path: String,
+        size: usize,
+        listed: bool,
+        /// Children already left, to notice when one is entered again. Only
+        /// the last one unless every child is tracked.
+        left: HashSet<String>,
+    }
+
+    impl OpenDir {
+        fn new(name: String, path: String) -> Self {
+            OpenDir {
+                name,
+                path,
+                size: 0,
+                listed: false,
+                left: HashSet::new(),
+            }
+        }
+    }
+
+    fn leave(stack: &mut Vec<OpenDir>, track_children: bool, emit: &mut impl FnMut(&str, usize)) {
+        if let Some(dir) = stack.pop() {
+            emit(&dir.path, dir.size);
+            if let Some(parent) = stack.last_mut() {
+                parent.size += dir.size;
+                if !track_children {
+                    parent.left.clear();
+                }
+                parent.left.insert(dir.name);
+            }
+        }
+    }
+
+    let mut stack = vec![OpenDir::new("/".to_string(), "/".to_string())];
+    let mut warnings = vec![];
+    let mut awaiting_pwd = false;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if std::mem::take(&mut awaiting_pwd) && line.starts_with('/') {
+            continue;
+        }
+
+        let unsupported = match line.parse::<ShellLine>() {
+            Ok(ShellLine::InputLine(Command::CdRoot)) => {
+                while stack.len() > 1 {
+                    leave(&mut stack, track_children, &mut emit);
+                }
+                false
+            }
+            Ok(ShellLine::InputLine(Command::CdUp)) => {
+                if stack.len() > 1 {
+                    leave(&mut stack, track_children, &mut emit);
+                }
+                false
+            }
+            Ok(ShellLine::InputLine(Command::CdDown(dir))) if !dir.contains('/') => {
+                let parent = stack.last().expect("Root is never left");
+                let path = join_path(&parent.path, &dir);
+                if parent.left.contains(&dir) {
+                    warnings.push(Warning {
+                        line: i + 1,
+                        message: format!("{path} entered again, its size may be counted twice"),
+                    });
+                }
+                stack.push(OpenDir::new(dir, path));
+                false
+            }
+            Ok(ShellLine::InputLine(Command::Touch { size, name })) if !name.contains('/') => {
+                stack.last_mut().expect("Root is never left").size += size;
+                false
+            }
+            Ok(ShellLine::OutputLine(File::RegularFile { size, .. })) => {
+                stack.last_mut().expect("Root is never left").size += size;
+                false
+            }
+            Ok(ShellLine::InputLine(Command::Ls)) => {
+                let current = stack.last_mut().expect("Root is never left");
+                if std::mem::replace(&mut current.listed, true) {
+                    warnings.push(Warning {
+                        line: i + 1,
+                        message: format!(
+                            "{} listed again, its files may be counted twice",
+                            current.path
+                        ),
+                    });
+                }
+                false
+            }
+            Ok(ShellLine::InputLine(Command::Pwd)) => {
+                awaiting_pwd = true;
+                false
+            }
+            Ok(ShellLine::OutputLine(File::Directory { .. })) => false,
+            Ok(ShellLine::InputLine(_)) => true,
+            Err(err) => {
+                warnings.push(Warning {
+                    line: i + 1,
+                    message: format!("ignored: {err}"),
+                });
+                false
+            }
+        };
+
+        if unsupported {
+            warnings.push(Warning {
+                line: i + 1,
+                message: format!("ignored in streaming mode: {line}"),
+            });
+        }
+    }
+
+    while !stack.is_empty() {
+        leave(&mut stack, track_children, &mut emit);
+    }
+
+    Ok(warnings)
+}
+
+fn stream_part_one<R: BufRead>(reader: R, track_children: bool) -> Result<(usize, Vec<Warning>)> {
+    let mut total = 0;
+    let warnings = stream_sizes(reader, track_children, |_, size| {
+        if size < 100_000 {
+            total += size;
+        }
+    })?;
+    Ok((total, warnings))
+}
+
+/// Part two needs the used space before it can pick a directory, so the
+/// transcript is streamed twice: once for the root size and once to search.
+fn stream_part_two<R: BufRead>(
+    open: impl Fn() -> Result<R>,
+    track_children: bool,
+) -> Result<(usize, Vec<Warning>)> {
+    let mut used_space = 0;
+    let warnings = stream_sizes(open()?, track_children, |_, size| used_space = size)?;
+    let needed_space = used_space.saturating_sub(70_000_000 - 30_000_000);
+
+    let mut smallest = used_space;
+    stream_sizes(open()?, track_children, |_, size| {
+        if size > needed_space && size < smallest {
+            smallest = size;
+        }
+    })?;
+    Ok((smallest, warnings))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SizeDistribution {
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+    /// Spreads sizes evenly across orders of magnitude, like real disks.
+    LogUniform {
+        min: usize,
+        max: usize,
+    },
+}
+
+impl SizeDistribution {
+    fn sample(&self, rng: &mut StdRng) -> usize {
+        match *self {
+            SizeDistribution::Uniform { min, max } => rng.gen_range(min..=max),
+            SizeDistribution::LogUniform { min, max } => {
+                let exponent = rng.gen_range((min.max(1) as f64).ln()..=(max as f64).ln());
+                (exponent.exp() as usize).clamp(min, max)
+            }
+        }
+    }
+}
+
+impl FromStr for SizeDistribution {
+    type Err = anyhow::Error;
+
+    /// Parses `uniform:MIN-MAX` or `log:MIN-MAX`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, range) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid size distribution {s}"))?;
+        let (min, max) = range
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Invalid size range {range}"))?;
+        let (min, max) = (min.parse()?, max.parse()?);
+        if min > max {
+            return Err(anyhow!("Empty size range {range}"));
+        }
+        match kind {
+            "uniform" => Ok(SizeDistribution::Uniform { min, max }),
+            "log" if max == 0 => Err(anyhow!("Log size range {range} needs a positive max")),
+            "log" => Ok(SizeDistribution::LogUniform { min, max }),
+            _ => Err(anyhow!("Unknown size distribution {kind}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GeneratorConfig {
+    seed: u64,
+    max_depth: usize,
+    max_dirs: usize,
+    max_files: usize,
+    sizes: SizeDistribution,
+    /// Chance of leaving a directory with `cd /` and an absolute walk back
+    /// to its parent instead of `cd ..`. Streaming mode needs this at zero.
+    jump_probability: f64,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            max_depth: 6,
+            max_dirs: 4,
+            max_files: 5,
+            sizes: SizeDistribution::LogUniform {
+                min: 1,
+                max: 400_000,
+            },
+            jump_probability: 0.0,
+        }
+    }
+}
+
+/// A random transcript together with the size of every directory in it,
+/// keyed by absolute path, to be used as an oracle.
+struct GeneratedTranscript {
+    transcript: String,
+    sizes: BTreeMap<String, usize>,
+}
+
+fn generate_transcript(config: &GeneratorConfig) -> GeneratedTranscript {
+    fn random_name(rng: &mut StdRng, taken: &mut HashSet<String>) -> String {
+        loop {
+            let len = rng.gen_range(1..=8);
+            let mut name = (0..len)
+                .map(|_| rng.gen_range(b'a'..=b'z') as char)
+                .collect::<String>();
+            if rng.gen_bool(0.3) {
+                name.push('.');
+                name.push_str(["txt", "dat", "log", "lst", "ext"][rng.gen_range(0..5)]);
+            }
+            if taken.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
+
+    fn go(
+        config: &GeneratorConfig,
+        rng: &mut StdRng,
+        path: &[String],
+        lines: &mut Vec<String>,
+        sizes: &mut BTreeMap<String, usize>,
+    ) -> usize {
+        let mut taken = HashSet::new();
+        let dirs = if path.len() < config.max_depth {
+            (0..rng.gen_range(0..=config.max_dirs))
+                .map(|_| random_name(rng, &mut taken))
+                .collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+        let files = (0..rng.gen_range(0..=config.max_files))
+            .map(|_| (random_name(rng, &mut taken), config.sizes.sample(rng)))
+            .collect::<Vec<_>>();
+
+        lines.push("$ ls".to_string());
+        let mut listing = dirs
+            .iter()
+            .map(|dir| format!("dir {dir}"))
+            .chain(files.iter().map(|(name, size)| format!("{size} {name}")))
+            .collect::<Vec<_>>();
+        // Listings are not sorted in the puzzle input either.
+        for i in (1..listing.len()).rev() {
+            listing.swap(i, rng.gen_range(0..=i));
+        }
+        lines.extend(listing);
+
+        let mut size = files.iter().map(|(_, size)| size).sum::<usize>();
+        for dir in dirs {
+            let mut child_path = path.to_vec();
+            child_path.push(dir.clone());
+            lines.push(format!("$ cd {dir}"));
+            size += go(config, rng, &child_path, lines, sizes);
+
+            if !path.is_empty() && rng.gen_bool(config.jump_probability) {
+                lines.push("$ cd /".to_string());
+                lines.push(format!("$ cd {}", path.join("/")));
+            } else {
+                lines.push("$ cd ..".to_string());
+            }
+        }
+
+        sizes.insert(format!("/{}", path.join("/")), size);
+        size
+    }
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut lines = vec!["$ cd /".to_string()];
+    let mut sizes = BTreeMap::new();
+    go(config, &mut rng, &[], &mut lines, &mut sizes);
+
+    GeneratedTranscript {
+        transcript: lines.join("\n"),
+        sizes,
+    }
 }
 
 fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    if let [_, command, old, new] = &args[..] {
+        if command == "diff" {
+            let (old_fs, old_warnings) = run_session(&std::fs::read_to_string(old)?);
+            let (new_fs, new_warnings) = run_session(&std::fs::read_to_string(new)?);
+            old_warnings
+                .iter()
+                .for_each(|warning| eprintln!("warning: {old}: {warning}"));
+            new_warnings
+                .iter()
+                .for_each(|warning| eprintln!("warning: {new}: {warning}"));
+            diff(&old_fs, &new_fs)
+                .iter()
+                .for_each(|change| println!("{change}"));
+            return Ok(());
+        }
+    }
+
+    if let [_, command, transcript, snapshot] = &args[..] {
+        if command == "save" {
+            let (file_system, warnings) = run_session(&std::fs::read_to_string(transcript)?);
+            warnings
+                .iter()
+                .for_each(|warning| eprintln!("warning: {transcript}: {warning}"));
+            return file_system.save(snapshot);
+        }
+    }
+
+    if let [_, command, seed, options @ ..] = &args[..] {
+        if command == "generate" {
+            let mut config = GeneratorConfig {
+                seed: seed.parse()?,
+                ..GeneratorConfig::default()
+            };
+            match options {
+                [] => {}
+                [depth, dirs, files, sizes] => {
+                    config.max_depth = depth.parse()?;
+                    config.max_dirs = dirs.parse()?;
+                    config.max_files = files.parse()?;
+                    config.sizes = sizes.parse()?;
+                }
+                _ => return Err(anyhow!("Usage: generate SEED [DEPTH DIRS FILES SIZES]")),
+            }
+            let generated = generate_transcript(&config);
+            println!("{}", generated.transcript);
+            generated
+                .sizes
+                .iter()
+                .for_each(|(path, size)| eprintln!("{size} {path}"));
+            return Ok(());
+        }
+    }
+
+    if let [_, command, path, options @ ..] = &args[..] {
+        if command == "stream" {
+            let track_children = match options {
+                [] => false,
+                [option] if option == "--track-children" => true,
+                _ => return Err(anyhow!("Usage: stream FILE [--track-children]")),
+            };
+            let open = || Ok(BufReader::new(std::fs::File::open(path)?));
+            let (solution_1, warnings) = stream_part_one(open()?, track_children)?;
+            warnings
+                .iter()
+                .for_each(|warning| eprintln!("warning: {path}: {warning}"));
+            println!("The solution of part one is {solution_1}");
+            let (solution_2, _) = stream_part_two(open, track_children)?;
+            println!("The solution of part two is {solution_2}");
+            return Ok(());
+        }
+    }
+
+    if let [_, command, path] = &args[..] {
+        if command == "load" {
+            let report = FileSystem::load(path)?.report();
+            println!("The solution of part one is {:?}", part_one(&report));
+            println!("The solution of part two is {:?}", part_two(&report));
+            return Ok(());
+        }
+    }
+
     let solution_1 = solve_part_one();
 
     println!("The solution of part one is {:?}", solution_1);
@@ -166,7 +1057,7 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn run_session() -> FileSystem {
+fn run_session(input: &str) -> (FileSystem, Vec<Warning>) {
     let file_system = FileSystem {
         root: Rc::new(RefCell::new(FileSystemNode {
             parent: None,
@@ -174,45 +1065,43 @@ fn run_session() -> FileSystem {
             files: vec![],
         })),
     };
-    let _run_session = include_str!("./day7.input")
+    let session = input
         .lines()
-        .map(|line| line.parse::<ShellLine>().unwrap())
-        .fold(
-            Session {
-                current_dir: Rc::clone(&file_system.root),
-            },
-            |session, shell_line| match shell_line {
-                ShellLine::OutputLine(File::Directory { .. }) => session,
-                ShellLine::OutputLine(file) => session.add_file(file),
-                ShellLine::InputLine(Command::CdUp) => session.cd_up(),
-                ShellLine::InputLine(Command::CdDown(dir)) => session.cd_down(&dir.to_owned()),
-                ShellLine::InputLine(Command::Ls) => session,
-            },
-        );
+        .enumerate()
+        .fold(Session::new(&file_system.root), |session, (i, line)| {
+            session.apply(i + 1, line)
+        });
 
-    file_system
+    (file_system, session.warnings)
 }
 
-fn solve_part_one() -> Result<usize> {
-    let file_system = run_session();
-    Ok(file_system
-        .report()
+fn part_one(report: &[(String, usize)]) -> usize {
+    report
         .iter()
         .filter(|(_, size)| *size < 100_000)
-        .fold(0, |acc, (_, size)| acc + size))
+        .fold(0, |acc, (_, size)| acc + size)
 }
 
-fn solve_part_two() -> Result<usize> {
-    let report = run_session().report();
+fn part_two(report: &[(String, usize)]) -> usize {
     let used_space = report.last().unwrap().1;
-    let needed_space = used_space - (70_000_000 - 30_000_000);
-    Ok(report.iter().fold(used_space, |acc, (_, size)| {
+    let needed_space = used_space.saturating_sub(70_000_000 - 30_000_000);
+    report.iter().fold(used_space, |acc, (_, size)| {
         if *size > needed_space && *size < acc {
             *size
         } else {
             acc
         }
-    }))
+    })
+}
+
+fn solve_part_one() -> Result<usize> {
+    let (file_system, _) = run_session(include_str!("./day7.input"));
+    Ok(part_one(&file_system.report()))
+}
+
+fn solve_part_two() -> Result<usize> {
+    let report = run_session(include_str!("./day7.input")).0.report();
+    Ok(part_two(&report))
 }
 
 #[cfg(test)]
@@ -222,12 +1111,32 @@ mod tests {
 
     #[test]
     fn command_parse() {
+        let command = "$ cd /".parse::<Command>();
+        assert_eq!(command.unwrap(), Command::CdRoot);
         let command = "$ cd ..".parse::<Command>();
         assert_eq!(command.unwrap(), Command::CdUp);
         let command = "$ cd a".parse::<Command>();
         assert_eq!(command.unwrap(), Command::CdDown("a".to_string()));
         let command = "$ ls".parse::<Command>();
         assert_eq!(command.unwrap(), Command::Ls);
+        let command = "$ ls a/e".parse::<Command>();
+        assert_eq!(command.unwrap(), Command::LsPath("a/e".to_string()));
+        let command = "$ pwd".parse::<Command>();
+        assert_eq!(command.unwrap(), Command::Pwd);
+        let command = "$ mkdir x".parse::<Command>();
+        assert_eq!(command.unwrap(), Command::Mkdir("x".to_string()));
+        let command = "$ rm /d/k".parse::<Command>();
+        assert_eq!(command.unwrap(), Command::Rm("/d/k".to_string()));
+        let command = "$ touch 10 y".parse::<Command>();
+        assert_eq!(
+            command.unwrap(),
+            Command::Touch {
+                size: 10,
+                name: "y".to_string()
+            }
+        );
+        assert!("$ touch ten y".parse::<Command>().is_err());
+        assert!("$ ln -s a b".parse::<Command>().is_err());
     }
 
     #[test]
@@ -322,4 +1231,324 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn diff_snapshots() {
+        let (old, _) = run_session(include_str!("./day7.test"));
+        let (new, _) = run_session(
+            &include_str!("./day7.test")
+                .replace("584 i", "600 i")
+                .replace("7214296 k\n", "")
+                .replace("$ cd e\n", "$ cd x\n$ ls\n10 y\n$ cd ..\n$ cd e\n"),
+        );
+
+        assert!(diff(&old, &old).is_empty());
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                Change::Resized {
+                    path: "/".to_string(),
+                    kind: EntryKind::Directory,
+                    old_size: 48381165,
+                    new_size: 41166895,
+                },
+                Change::Resized {
+                    path: "/a".to_string(),
+                    kind: EntryKind::Directory,
+                    old_size: 94853,
+                    new_size: 94879,
+                },
+                Change::Resized {
+                    path: "/a/e".to_string(),
+                    kind: EntryKind::Directory,
+                    old_size: 584,
+                    new_size: 600,
+                },
+                Change::Resized {
+                    path: "/a/e/i".to_string(),
+                    kind: EntryKind::RegularFile,
+                    old_size: 584,
+                    new_size: 600,
+                },
+                Change::Added {
+                    path: "/a/x".to_string(),
+                    kind: EntryKind::Directory,
+                    size: 10,
+                },
+                Change::Added {
+                    path: "/a/x/y".to_string(),
+                    kind: EntryKind::RegularFile,
+                    size: 10,
+                },
+                Change::Resized {
+                    path: "/d".to_string(),
+                    kind: EntryKind::Directory,
+                    old_size: 24933642,
+                    new_size: 17719346,
+                },
+                Change::Removed {
+                    path: "/d/k".to_string(),
+                    kind: EntryKind::RegularFile,
+                    size: 7214296,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn session_commands() {
+        let (file_system, warnings) = run_session(
+            "$ cd /
+$ mkdir a/b
+$ touch 10 a/b/x
+$ cd a/b
+$ pwd
+/a/b
+$ touch 5 ../y
+$ touch 7 ../y
+$ ls /c
+dir e
+20 z
+$ rm /c/z
+$ rm /c/missing
+$ cd ..
+$ pwd
+/a/b
+$ ln -s b c
+$ cd ..
+$ cd ..
+$ ls
+30 w",
+        );
+
+        assert_eq!(
+            warnings,
+            vec![
+                Warning {
+                    line: 13,
+                    message: "rm: cannot remove /c/missing: no such file or directory".to_string()
+                },
+                Warning {
+                    line: 16,
+                    message: "pwd printed /a/b but the session is at /a".to_string()
+                },
+                Warning {
+                    line: 17,
+                    message: "ignored: Invalid Command input $ ln -s b c".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            file_system.entries().into_iter().collect::<Vec<_>>(),
+            vec![
+                ("/".to_string(), (EntryKind::Directory, 47)),
+                ("/a".to_string(), (EntryKind::Directory, 17)),
+                ("/a/b".to_string(), (EntryKind::Directory, 10)),
+                ("/a/b/x".to_string(), (EntryKind::RegularFile, 10)),
+                ("/a/y".to_string(), (EntryKind::RegularFile, 7)),
+                ("/c".to_string(), (EntryKind::Directory, 0)),
+                ("/c/e".to_string(), (EntryKind::Directory, 0)),
+                ("/w".to_string(), (EntryKind::RegularFile, 30)),
+            ]
+        );
+    }
+
+    #[test]
+    fn streaming_matches_tree() {
+        for input in [include_str!("./day7.test"), include_str!("./day7.input")] {
+            let mut streamed = BTreeMap::new();
+            let warnings = stream_sizes(input.as_bytes(), false, |path, size| {
+                streamed.insert(path.to_string(), size);
+            })
+            .unwrap();
+            assert!(warnings.is_empty());
+
+            let (file_system, _) = run_session(input);
+            let expected = file_system
+                .entries()
+                .into_iter()
+                .filter(|(_, (kind, _))| *kind == EntryKind::Directory)
+                .map(|(path, (_, size))| (path, size))
+                .collect::<BTreeMap<_, _>>();
+            assert_eq!(streamed, expected);
+        }
+
+        assert_eq!(
+            stream_part_one(include_str!("./day7.input").as_bytes(), false)
+                .unwrap()
+                .0,
+            solve_part_one().unwrap()
+        );
+        assert_eq!(
+            stream_part_two(|| Ok(include_str!("./day7.input").as_bytes()), false)
+                .unwrap()
+                .0,
+            solve_part_two().unwrap()
+        );
+    }
+
+    #[test]
+    fn streaming_warns_on_repeated_directories() {
+        let lines = |input: &str, track_children| {
+            let warnings = stream_sizes(input.as_bytes(), track_children, |_, _| {}).unwrap();
+            warnings.iter().map(|w| w.line).collect::<Vec<_>>()
+        };
+
+        let input = "$ cd /\n$ ls\ndir a\n$ cd a\n$ ls\n30000 x\n$ cd ..\n\
+                     $ cd a\n$ ls\n30000 x\n$ cd ..\n";
+        assert_eq!(lines(input, false), vec![8]);
+        assert_eq!(lines(input, true), vec![8]);
+        let warnings = stream_sizes(input.as_bytes(), false, |_, _| {}).unwrap();
+        assert!(warnings[0].message.starts_with("/a entered again"));
+
+        let input = "$ cd /\n$ ls\n10 x\n$ ls\n10 x\n";
+        assert_eq!(lines(input, false), vec![4]);
+        let warnings = stream_sizes(input.as_bytes(), false, |_, _| {}).unwrap();
+        assert!(warnings[0].message.starts_with("/ listed again"));
+
+        let input = "$ cd /\n$ ls\ndir a\ndir b\n$ cd a\n$ ls\n$ cd ..\n$ cd b\n$ ls\n";
+        assert!(lines(input, true).is_empty());
+
+        // Going back to a child left before the last one is only noticed when
+        // every child is tracked.
+        let input = "$ cd /\n$ cd a\n$ cd ..\n$ cd b\n$ cd ..\n$ cd a\n";
+        assert!(lines(input, false).is_empty());
+        assert_eq!(lines(input, true), vec![6]);
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let (file_system, _) = run_session(include_str!("./day7.input"));
+        let expected = file_system.entries();
+
+        let from_json = FileSystem::from_json(&file_system.to_json().unwrap()).unwrap();
+        assert_eq!(from_json.entries(), expected);
+        assert_eq!(part_one(&from_json.report()), solve_part_one().unwrap());
+        assert_eq!(part_two(&from_json.report()), solve_part_two().unwrap());
+
+        let from_bytes = FileSystem::from_bytes(&file_system.to_bytes().unwrap()).unwrap();
+        assert_eq!(from_bytes.entries(), expected);
+
+        let (small, _) = run_session("$ cd /\n$ ls\n10 a\ndir b");
+        assert_eq!(
+            small.to_json().unwrap(),
+            r#"{
+  "version": 2,
+  "root": {
+    "dirs": {
+      "b": {
+        "dirs": {},
+        "files": []
+      }
+    },
+    "files": [
+      {
+        "name": "a",
+        "size": 10
+      }
+    ]
+  }
+}"#
+        );
+        assert!(
+            FileSystem::from_json(r#"{"version": 3, "root": {"dirs": {}, "files": []}}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn snapshot_files_are_regular() {
+        for json in [
+            r#"{"version":1,"root":{"dirs":{},"files":[{"Directory":{"name":"x"}}]}}"#,
+            r#"{"version":2,"root":{"dirs":{},"files":[{"Directory":{"name":"x"}}]}}"#,
+            r#"{"version":2,"root":{"dirs":{},"files":[{"name":"x"}]}}"#,
+        ] {
+            assert!(FileSystem::from_json(json).is_err(), "{json}");
+        }
+
+        let json = r#"{"version":2,"root":{"dirs":{"x":{"dirs":{},"files":[]}},"files":[{"name":"a","size":10}]}}"#;
+        let file_system = FileSystem::from_json(json).unwrap();
+        assert_eq!(part_one(&file_system.report()), 10);
+    }
+
+    #[test]
+    fn size_distribution_parse() {
+        assert_eq!(
+            "uniform:0-0".parse::<SizeDistribution>().unwrap(),
+            SizeDistribution::Uniform { min: 0, max: 0 }
+        );
+        assert_eq!(
+            "log:0-10".parse::<SizeDistribution>().unwrap(),
+            SizeDistribution::LogUniform { min: 0, max: 10 }
+        );
+        for s in [
+            "log:0-0",
+            "log:5-2",
+            "uniform:5-2",
+            "uniform:1",
+            "normal:1-2",
+            "log:a-2",
+        ] {
+            assert!(s.parse::<SizeDistribution>().is_err(), "{s}");
+        }
+
+        let mut rng = StdRng::seed_from_u64(30);
+        let sizes = "log:0-1".parse::<SizeDistribution>().unwrap();
+        assert!((0..100).all(|_| sizes.sample(&mut rng) <= 1));
+    }
+
+    #[test]
+    fn generated_transcripts_match_oracle() {
+        for seed in 0..50 {
+            let config = GeneratorConfig {
+                seed,
+                jump_probability: if seed % 2 == 0 { 0.0 } else { 0.3 },
+                sizes: if seed % 3 == 0 {
+                    "uniform:1-10000000".parse().unwrap()
+                } else {
+                    GeneratorConfig::default().sizes
+                },
+                ..GeneratorConfig::default()
+            };
+            let generated = generate_transcript(&config);
+
+            let (file_system, warnings) = run_session(&generated.transcript);
+            assert!(warnings.is_empty());
+            let directories = file_system
+                .entries()
+                .into_iter()
+                .filter(|(_, (kind, _))| *kind == EntryKind::Directory)
+                .map(|(path, (_, size))| (path, size))
+                .collect::<BTreeMap<_, _>>();
+            assert_eq!(directories, generated.sizes, "seed {seed}");
+
+            let used_space = generated.sizes["/"];
+            let needed_space = used_space.saturating_sub(40_000_000);
+            let report = file_system.report();
+            assert_eq!(
+                part_one(&report),
+                generated
+                    .sizes
+                    .values()
+                    .filter(|size| **size < 100_000)
+                    .sum::<usize>()
+            );
+            assert_eq!(
+                part_two(&report),
+                generated
+                    .sizes
+                    .values()
+                    .filter(|size| **size > needed_space)
+                    .fold(used_space, |acc, size| acc.min(*size))
+            );
+
+            if config.jump_probability == 0.0 {
+                let mut streamed = BTreeMap::new();
+                stream_sizes(generated.transcript.as_bytes(), false, |path, size| {
+                    streamed.insert(path.to_string(), size);
+                })
+                .unwrap();
+                assert_eq!(streamed, generated.sizes, "seed {seed}");
+            }
+        }
+    }
 }
@@ -11,6 +11,161 @@ where
         .collect())
 }
 
+/// Inclusive range of integers, such as the sections assigned to an elf.
+/// It always holds at least one value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Interval {
+    start: u32,
+    end: u32,
+}
+
+impl Interval {
+    /// `None` when `start` comes after `end`.
+    pub fn new(start: u32, end: u32) -> Option<Self> {
+        (start <= end).then_some(Self { start, end })
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    pub fn point(value: u32) -> Self {
+        Self {
+            start: value,
+            end: value,
+        }
+    }
+
+    /// Number of values in the interval.
+    pub fn length(&self) -> u64 {
+        u64::from(self.end - self.start) + 1
+    }
+
+    pub fn contains_point(&self, value: u32) -> bool {
+        self.start <= value && value <= self.end
+    }
+
+    pub fn contains(&self, other: &Self) -> bool {
+        self.start <= other.start && other.end <= self.end
+    }
+
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    /// Whether the two intervals overlap or one ends right before the other
+    /// starts, so that together they form a single interval.
+    pub fn touches(&self, other: &Self) -> bool {
+        self.start <= other.end.saturating_add(1) && other.start <= self.end.saturating_add(1)
+    }
+
+    pub fn intersection(&self, other: &Self) -> Option<Self> {
+        Self::new(self.start.max(other.start), self.end.min(other.end))
+    }
+
+    /// `None` when there is a gap between the intervals.
+    pub fn union(&self, other: &Self) -> Option<Self> {
+        self.touches(other).then(|| Self {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        })
+    }
+
+    /// The values of `self` not in `other`: none, one interval, or two when
+    /// `other` cuts `self` in the middle.
+    pub fn difference(&self, other: &Self) -> Vec<Self> {
+        if !self.overlaps(other) {
+            return vec![*self];
+        }
+        let before = (self.start < other.start).then(|| Self {
+            start: self.start,
+            end: other.start - 1,
+        });
+        let after = (other.end < self.end).then(|| Self {
+            start: other.end + 1,
+            end: self.end,
+        });
+        before.into_iter().chain(after).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Interval;
+
+    fn interval(start: u32, end: u32) -> Interval {
+        Interval::new(start, end).unwrap()
+    }
+
+    #[test]
+    fn test_interval_basics() {
+        assert_eq!(Interval::new(5, 3), None);
+        assert_eq!(Interval::new(4, 4), Some(Interval::point(4)));
+        assert_eq!((interval(2, 8).start(), interval(2, 8).end()), (2, 8));
+        assert_eq!(interval(2, 8).length(), 7);
+        assert_eq!(Interval::point(6).length(), 1);
+        assert_eq!(interval(0, u32::MAX).length(), 1 << 32);
+
+        assert!(interval(2, 8).contains(&interval(3, 7)));
+        assert!(interval(2, 8).contains(&interval(2, 8)));
+        assert!(interval(2, 8).contains(&Interval::point(8)));
+        assert!(!interval(3, 7).contains(&interval(2, 8)));
+        assert!(interval(2, 8).contains_point(2));
+        assert!(!interval(2, 8).contains_point(9));
+
+        assert!(interval(5, 7).overlaps(&interval(7, 9)));
+        assert!(interval(7, 9).overlaps(&interval(5, 7)));
+        assert!(Interval::point(6).overlaps(&interval(4, 6)));
+        assert!(!interval(2, 4).overlaps(&interval(5, 8)));
+        assert!(interval(2, 4).touches(&interval(5, 8)));
+        assert!(!interval(2, 4).touches(&interval(6, 8)));
+    }
+
+    #[test]
+    fn test_interval_algebra() {
+        assert_eq!(
+            interval(5, 7).intersection(&interval(7, 9)),
+            Some(Interval::point(7))
+        );
+        assert_eq!(interval(2, 4).intersection(&interval(6, 8)), None);
+        assert_eq!(
+            interval(2, 8).intersection(&interval(3, 7)),
+            Some(interval(3, 7))
+        );
+
+        assert_eq!(interval(2, 4).union(&interval(5, 8)), Some(interval(2, 8)));
+        assert_eq!(interval(2, 6).union(&interval(4, 8)), Some(interval(2, 8)));
+        assert_eq!(interval(2, 4).union(&interval(6, 8)), None);
+        assert_eq!(
+            Interval::point(u32::MAX).union(&Interval::point(u32::MAX - 1)),
+            Some(interval(u32::MAX - 1, u32::MAX))
+        );
+
+        assert_eq!(
+            interval(2, 8).difference(&interval(4, 5)),
+            vec![interval(2, 3), interval(6, 8)]
+        );
+        assert_eq!(
+            interval(2, 8).difference(&interval(0, 5)),
+            vec![interval(6, 8)]
+        );
+        assert_eq!(
+            interval(2, 8).difference(&interval(8, 9)),
+            vec![interval(2, 7)]
+        );
+        assert_eq!(
+            interval(2, 8).difference(&interval(9, 9)),
+            vec![interval(2, 8)]
+        );
+        assert_eq!(interval(2, 8).difference(&interval(1, 9)), vec![]);
+        assert_eq!(Interval::point(0).difference(&Interval::point(0)), vec![]);
+    }
+}
+
 /*
 struct Foo {
     bar: usize,